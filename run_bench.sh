@@ -0,0 +1,33 @@
+#!/bin/bash
+# Compare end to end time of the HashMap and BTreeMap (btree feature) ledgers,
+# and of bounded and unbounded shard channels
+# Usage: run_bench.sh [rows]
+
+ROWS="${1:-2000000}"
+
+MYTMPDIR=$(mktemp -d "${TMPDIR:-/tmp}/run_bench.XXXXXXXXX") || exit 1
+trap 'rm -rf -- "$MYTMPDIR"' EXIT
+
+# few clients each with many transactions vs many clients with few each
+./test_suites/generate_big_csv.py --rows "$ROWS" --clients 16 > "$MYTMPDIR/few_large.csv" || exit 1
+./test_suites/generate_big_csv.py --rows "$ROWS" --clients 65536 > "$MYTMPDIR/many_small.csv" || exit 1
+
+for FEATURES in "" "btree"; do
+    cargo build -q --release --features "$FEATURES" --target-dir "$MYTMPDIR/target-$FEATURES" || exit 1
+    for INPUT in few_large many_small; do
+        TIMEFORMAT="ledger=${FEATURES:-hashmap} input=$INPUT rows=$ROWS seconds=%R"
+        time "$MYTMPDIR/target-$FEATURES/release/paytoy" "$MYTMPDIR/$INPUT.csv" > /dev/null || exit 1
+    done
+done
+
+cargo build -q --release --target-dir "$MYTMPDIR/target-" || exit 1
+for CHANNEL in bounded unbounded; do
+    TIMEFORMAT="channel=$CHANNEL input=many_small rows=$ROWS seconds=%R"
+    time "$MYTMPDIR/target-/release/paytoy" --channel "$CHANNEL" "$MYTMPDIR/many_small.csv" > /dev/null || exit 1
+done
+
+# pre-sized maps vs growing them as clients and tx ids arrive
+for PRESIZE in "" "--expected-clients 65536 --expected-transactions $ROWS"; do
+    TIMEFORMAT="presize=${PRESIZE:+yes}${PRESIZE:-no} input=many_small rows=$ROWS seconds=%R"
+    time "$MYTMPDIR/target-/release/paytoy" $PRESIZE "$MYTMPDIR/many_small.csv" > /dev/null || exit 1
+done
@@ -0,0 +1,22 @@
+#!/bin/bash
+# Compare peak RSS of the default in memory combine and --low-memory output
+# Usage: run_bench_memory.sh [rows]
+
+ROWS="${1:-2000000}"
+
+MYTMPDIR=$(mktemp -d "${TMPDIR:-/tmp}/run_bench_memory.XXXXXXXXX") || exit 1
+trap 'rm -rf -- "$MYTMPDIR"' EXIT
+
+# as many clients as possible so the combine stage holds the most balances
+./test_suites/generate_big_csv.py --rows "$ROWS" --clients 65536 > "$MYTMPDIR/many_clients.csv" || exit 1
+
+cargo build -q --release || exit 1
+
+for FLAGS in "" "--low-memory"; do
+    RSS=$(python3 -c '
+import resource, subprocess, sys
+subprocess.run(sys.argv[1:], stdout=subprocess.DEVNULL, check=True)
+print(resource.getrusage(resource.RUSAGE_CHILDREN).ru_maxrss)
+' ./target/release/paytoy $FLAGS "$MYTMPDIR/many_clients.csv") || exit 1
+    echo "mode=${FLAGS:-default} rows=$ROWS peak_rss_kib=$RSS"
+done
@@ -0,0 +1,63 @@
+use anyhow::{Context, Error};
+
+use std::collections::HashSet;
+
+use crate::ids::ClientId;
+
+/// The only clients whose transactions are processed, others are dropped
+/// before they reach the shards or use up their tx ids
+#[derive(Clone, Debug, Default)]
+pub struct AllowClients {
+    clients: HashSet<ClientId>,
+}
+
+impl AllowClients {
+    /// Parse a comma separated list of ids, e.g. `1,5,9`, or else read the
+    /// named file of ids separated by commas or new lines
+    pub fn parse(arg: &str) -> Result<Self, Error> {
+        let is_list = arg
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == ',' || c == ' ');
+        let text = if is_list {
+            arg.to_string()
+        } else {
+            std::fs::read_to_string(arg)
+                .with_context(|| format!("Reading allowed clients {}", arg))?
+        };
+        let clients = text
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                id.parse()
+                    .map(ClientId)
+                    .with_context(|| format!("Invalid client id {:?}", id))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { clients })
+    }
+
+    pub fn allows(&self, client: ClientId) -> bool {
+        self.clients.contains(&client)
+    }
+}
+
+#[test]
+fn test_allow_clients() -> Result<(), Error> {
+    use crate::testutil::write_test_csv;
+
+    let allow = AllowClients::parse("1, 5,9")?;
+    assert!(allow.allows(ClientId(5)));
+    assert!(!allow.allows(ClientId(2)));
+
+    let path = write_test_csv("allow.txt", "3\n4,7\n\n")?;
+    let allow = AllowClients::parse(&path);
+    std::fs::remove_file(&path)?;
+    let allow = allow?;
+    assert!(allow.allows(ClientId(4)));
+    assert!(allow.allows(ClientId(7)));
+    assert!(!allow.allows(ClientId(1)));
+
+    assert!(AllowClients::parse("1,70000").is_err());
+    Ok(())
+}
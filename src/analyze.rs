@@ -0,0 +1,79 @@
+use std::fmt::{Display, Formatter};
+
+use crate::ids::ClientId;
+
+/// Per shard transaction counts under modulo sharding by client id
+#[derive(Debug)]
+pub struct ShardLoad {
+    counts: Vec<u64>,
+}
+
+impl ShardLoad {
+    pub fn new(num_shards: u16) -> Self {
+        Self {
+            counts: vec![0; num_shards.into()],
+        }
+    }
+
+    /// Count one transaction against the shard its client routes to
+    pub fn add(&mut self, client: ClientId) {
+        let shard_id = client.id() as usize % self.counts.len();
+        self.counts[shard_id] += 1;
+    }
+
+    pub fn min(&self) -> u64 {
+        self.counts.iter().copied().min().unwrap_or_default()
+    }
+
+    pub fn max(&self) -> u64 {
+        self.counts.iter().copied().max().unwrap_or_default()
+    }
+
+    /// Population standard deviation of the per shard counts
+    pub fn stddev(&self) -> f64 {
+        let n = self.counts.len() as f64;
+        let mean = self.counts.iter().sum::<u64>() as f64 / n;
+        let variance = self
+            .counts
+            .iter()
+            .map(|c| (*c as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        variance.sqrt()
+    }
+}
+
+impl Display for ShardLoad {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "shards: {} min: {} max: {} stddev: {:.2}",
+            self.counts.len(),
+            self.min(),
+            self.max(),
+            self.stddev()
+        )
+    }
+}
+
+#[test]
+fn test_skewed_load() {
+    let mut load = ShardLoad::new(4);
+    // every client id is a multiple of the shard count, so all land on shard 0
+    for id in [0, 4, 8, 12, 16, 20, 24, 28] {
+        load.add(ClientId(id));
+    }
+    assert_eq!(load.min(), 0);
+    assert_eq!(load.max(), 8);
+    // counts [8, 0, 0, 0], mean 2, variance (36 + 4 + 4 + 4) / 4 = 12
+    assert_eq!(load.stddev(), 12f64.sqrt());
+    assert_eq!(load.to_string(), "shards: 4 min: 0 max: 8 stddev: 3.46");
+
+    let mut load = ShardLoad::new(4);
+    for id in 0..8 {
+        load.add(ClientId(id));
+    }
+    assert_eq!(load.min(), 2);
+    assert_eq!(load.max(), 2);
+    assert_eq!(load.stddev(), 0.0);
+}
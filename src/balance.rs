@@ -1,4 +1,5 @@
 use anyhow::{bail, Error};
+use clap::ValueEnum;
 use rust_decimal::Decimal;
 
 use std::collections::HashMap;
@@ -13,12 +14,68 @@ pub enum RecordType {
     Withdrawal,
 }
 
+impl RecordType {
+    /// The input type name the record came from
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordType::Deposit => "deposit",
+            RecordType::Withdrawal => "withdrawal",
+        }
+    }
+}
+
+/// What to do with a deposit or withdrawal over its per transaction limit
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum OverLimit {
+    /// Stop with an error naming the type and limit
+    #[default]
+    Error,
+    /// Ignore it, as with a withdrawal over the available funds
+    Ignore,
+}
+
+/// Rules applied when processing transactions, beyond the defaults
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Only deposits may be disputed, a dispute, resolve or chargeback
+    /// referencing a withdrawal is an error rather than being acted on
+    pub strict_tx_semantics: bool,
+    /// Check held never has more decimal places than this after any
+    /// transaction, catching arithmetic that introduced extra precision
+    pub paranoid_max_dp: Option<u32>,
+    /// Withdrawals that would leave available below this are ignored
+    pub keep_minimum: Decimal,
+    /// Stop with an `AccountLocked` error as soon as any chargeback locks an account
+    pub fail_on_lock: bool,
+    /// How many times one transaction may be disputed, later disputes are ignored
+    pub max_dispute_cycles: Option<u16>,
+    /// Note every client referenced, even only by ignored transactions, for
+    /// `Clients::empty_clients`
+    pub track_appeared: bool,
+    /// Count each client's transactions and when disputes were raised, for
+    /// `Clients::open_dispute_report`
+    pub track_disputes: bool,
+    /// Largest amount allowed for a single deposit
+    pub max_deposit: Option<Decimal>,
+    /// Largest amount allowed for a single withdrawal, independent of `max_deposit`
+    pub max_withdrawal: Option<Decimal>,
+    pub over_limit: OverLimit,
+    /// Warn when a dispute leaves more than this fraction of a client's funds held
+    pub held_ratio_alert: Option<Decimal>,
+    /// Resolve a dispute once this many more of the client's transactions
+    /// have been applied without it being charged back
+    pub auto_release_after: Option<u32>,
+}
+
 /// Record of a transaction in case of dispute
 #[derive(Debug, Eq, PartialEq)]
 pub struct TranRecord {
     rec_type: RecordType,
     amount: Decimal,
     disputed: bool,
+    /// Times this transaction has been disputed, a u16 fits in the padding
+    /// after the flags so records stay the same size
+    disputes: u16,
 }
 
 impl TranRecord {
@@ -27,69 +84,117 @@ impl TranRecord {
             rec_type,
             amount,
             disputed: false,
+            disputes: 0,
         }
     }
+
+    pub fn rec_type(&self) -> RecordType {
+        self.rec_type
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    /// Whether the transaction is currently under dispute
+    pub fn disputed(&self) -> bool {
+        self.disputed
+    }
 }
 
 /// Holds the balances for one client asset
+///
+/// Duplicate `TxId`s are detected by `dedup::SeenTx`, centrally before
+/// transactions are sharded. A balance only guards its own records, erroring
+/// rather than overwriting one, so a caller skipping that check can't orphan
+/// held funds.
 #[derive(Debug, Default)]
 pub struct Balance {
     available: Decimal,
     held: Decimal,
     locked: bool,
+    lock_reason: Option<String>,
     trans: HashMap<TxId, TranRecord>,
 }
 
 impl Balance {
+    /// Build a balance directly, e.g. for fixtures.
+    ///
+    /// Nothing is cross checked, keeping the parts consistent is the caller's
+    /// responsibility: `held` should be the disputed deposits in `trans` less
+    /// the disputed withdrawals, and `trans` ids must not be reused elsewhere.
+    pub fn from_parts(
+        available: Decimal,
+        held: Decimal,
+        locked: bool,
+        trans: HashMap<TxId, TranRecord>,
+    ) -> Self {
+        Self {
+            available,
+            held,
+            locked,
+            lock_reason: None,
+            trans,
+        }
+    }
+
+    /// Guard against overwriting a record, see `dedup::SeenTx` for duplicate detection
+    fn check_unused(&self, tx: TxId) -> Result<(), Error> {
+        if self.trans.contains_key(&tx) {
+            bail!("Reused transaction {}", tx.id());
+        }
+        Ok(())
+    }
+
     pub fn deposit(&mut self, tx: TxId, amount: Decimal) -> Result<(), Error> {
         if amount <= Decimal::ZERO {
             bail!("invalid amount {}", amount);
         }
+        self.check_unused(tx)?;
         if !self.locked {
-            let old = self
-                .trans
+            self.trans
                 .insert(tx, TranRecord::new(RecordType::Deposit, amount));
-            if let Some(old) = old {
-                self.trans.insert(tx, old);
-                bail!("Duplicate transaction {:?}", tx);
-            }
             self.available += amount;
         }
         Ok(())
     }
 
-    pub fn withdraw(&mut self, tx: TxId, amount: Decimal) -> Result<(), Error> {
+    /// Withdraw, ignored if available would drop below `minimum`
+    pub fn withdraw(&mut self, tx: TxId, amount: Decimal, minimum: Decimal) -> Result<(), Error> {
         if amount <= Decimal::ZERO {
             bail!("invalid amount {}", amount);
         }
-        if !self.locked && self.available >= amount {
-            let old = self
-                .trans
+        self.check_unused(tx)?;
+        let left = self.available.checked_sub(amount);
+        if !self.locked && matches!(left, Some(left) if left >= minimum) {
+            self.trans
                 .insert(tx, TranRecord::new(RecordType::Withdrawal, amount));
-            if let Some(old) = old {
-                self.trans.insert(tx, old);
-                bail!("Duplicate transaction {:?}", tx);
-            }
             self.available -= amount;
         }
         Ok(())
     }
 
-    pub fn dispute(&mut self, tx: TxId) -> Result<(), Error> {
+    /// Dispute, ignored once the transaction has been disputed `max_cycles` times
+    pub fn dispute(&mut self, tx: TxId, max_cycles: Option<u16>) -> Result<(), Error> {
         if self.locked {
             return Ok(());
         }
         let record = self.trans.get_mut(&tx);
         if let Some(record) = record {
+            if matches!(max_cycles, Some(max) if record.disputes >= max) {
+                return Ok(());
+            }
             match (record.rec_type, record.disputed) {
                 (RecordType::Deposit, false) => {
                     self.available -= record.amount;
                     self.held += record.amount;
                     record.disputed = true;
+                    record.disputes = record.disputes.saturating_add(1);
                 }
                 (RecordType::Withdrawal, false) => {
                     self.held -= record.amount;
                     record.disputed = true;
+                    record.disputes = record.disputes.saturating_add(1);
                 }
                 // Already disputed
                 (_, true) => (),
@@ -127,7 +232,7 @@ impl Balance {
         }
     }
 
-    pub fn chargeback(&mut self, tx: TxId) -> Result<(), Error> {
+    pub fn chargeback(&mut self, tx: TxId, reason: Option<String>) -> Result<(), Error> {
         if self.locked {
             return Ok(());
         }
@@ -138,12 +243,14 @@ impl Balance {
                     self.held -= record.amount;
                     record.disputed = false;
                     self.locked = true;
+                    self.lock_reason = reason;
                 }
                 (RecordType::Withdrawal, true) => {
                     self.available += record.amount;
                     self.held += record.amount;
                     record.disputed = false;
                     self.locked = true;
+                    self.lock_reason = reason;
                 }
                 // Not disputed, ignore
                 (_, false) => (),
@@ -154,18 +261,160 @@ impl Balance {
             Ok(())
         }
     }
+
+    /// Admin credit, no record is kept so it can't be disputed
+    pub fn mint(&mut self, amount: Decimal) -> Result<(), Error> {
+        if amount <= Decimal::ZERO {
+            bail!("invalid amount {}", amount);
+        }
+        self.available += amount;
+        Ok(())
+    }
+
+    /// Admin debit, no record is kept so it can't be disputed.
+    /// Errors rather than taking available negative.
+    pub fn burn(&mut self, amount: Decimal) -> Result<(), Error> {
+        if amount <= Decimal::ZERO {
+            bail!("invalid amount {}", amount);
+        }
+        if amount > self.available {
+            bail!("burn of {} exceeds available {}", amount, self.available);
+        }
+        self.available -= amount;
+        Ok(())
+    }
+
+    /// Opening balance credit, no record is kept so it can't be disputed
+    pub fn open(&mut self, amount: Decimal) {
+        self.available += amount;
+    }
+
+    /// Merge another balance for the same client into this one.
+    /// Errors without changing anything if both hold the same transaction id.
+    pub fn merge(&mut self, other: Balance) -> Result<(), Error> {
+        if let Some(tx) = other.trans.keys().find(|tx| self.trans.contains_key(tx)) {
+            bail!("Conflicting transaction {}", tx.id());
+        }
+        let (available, held) = match (
+            self.available.checked_add(other.available),
+            self.held.checked_add(other.held),
+        ) {
+            (Some(available), Some(held)) => (available, held),
+            _ => bail!("Overflow merging balances"),
+        };
+        self.available = available;
+        self.held = held;
+        if other.locked && !self.locked {
+            self.locked = true;
+            self.lock_reason = other.lock_reason;
+        }
+        self.trans.extend(other.trans);
+        Ok(())
+    }
+
+    /// Ids of transactions currently under dispute, in id order
+    pub fn open_disputes(&self) -> Vec<TxId> {
+        let mut open: Vec<TxId> = self
+            .trans
+            .iter()
+            .filter(|(_, record)| record.disputed)
+            .map(|(tx, _)| *tx)
+            .collect();
+        open.sort_by_key(|tx| tx.id());
+        open
+    }
+
+    /// Gross amounts deposited and withdrawn, whatever their dispute state.
+    /// Ignored withdrawals and admin adjustments aren't recorded so don't count.
+    pub fn flows(&self) -> (Option<Decimal>, Option<Decimal>) {
+        let sum = |rec_type| {
+            self.trans
+                .values()
+                .filter(|record| record.rec_type == rec_type)
+                .try_fold(Decimal::ZERO, |sum, record| sum.checked_add(record.amount))
+        };
+        (sum(RecordType::Deposit), sum(RecordType::Withdrawal))
+    }
+
+    /// The amount of a transaction currently under dispute
+    pub fn disputed_amount(&self, tx: TxId) -> Option<Decimal> {
+        self.trans
+            .get(&tx)
+            .filter(|record| record.disputed)
+            .map(|record| record.amount)
+    }
+
+    /// Nothing recorded and nothing left, e.g. every withdrawal failed
+    pub fn is_empty(&self) -> bool {
+        self.trans.is_empty() && self.available.is_zero() && self.held.is_zero()
+    }
+
+    /// Every recorded transaction, by tx id
+    pub fn records(&self) -> Vec<(TxId, &TranRecord)> {
+        let mut records: Vec<(TxId, &TranRecord)> = self
+            .trans
+            .iter()
+            .map(|(tx, record)| (*tx, record))
+            .collect();
+        records.sort_by_key(|(tx, _)| tx.id());
+        records
+    }
+
+    /// What kind of transaction a recorded id was, if known
+    pub fn record_type(&self, tx: TxId) -> Option<RecordType> {
+        self.trans.get(&tx).map(|record| record.rec_type)
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// available + held, or None if that would overflow
+    pub fn total(&self) -> Option<Decimal> {
+        self.available.checked_add(self.held)
+    }
+
+    /// held / (available + held), or None if the total isn't positive, e.g.
+    /// no funds at all, so there is no meaningful fraction
+    pub fn held_ratio(&self) -> Option<Decimal> {
+        match self.total() {
+            Some(total) if total > Decimal::ZERO => self.held.checked_div(total),
+            _ => None,
+        }
+    }
+
+    /// Why the account was locked, if the chargeback gave a reason
+    pub fn lock_reason(&self) -> Option<&str> {
+        self.lock_reason.as_deref()
+    }
 }
 
+/// Written in place of a total too large to represent
+pub const OVERFLOW: &str = "OVERFLOW";
+
 impl Display for Balance {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{},{},{},{}",
-            self.available,
-            self.held,
-            self.available + self.held,
-            self.locked
-        )
+        // Don't panic this late, all the processing has already succeeded
+        match self.total() {
+            Some(total) => write!(
+                f,
+                "{},{},{},{}",
+                self.available, self.held, total, self.locked
+            ),
+            None => write!(
+                f,
+                "{},{},{},{}",
+                self.available, self.held, OVERFLOW, self.locked
+            ),
+        }
     }
 }
 
@@ -175,12 +424,12 @@ fn test_dispute_deposit() -> Result<(), Error> {
     let mut balance = Balance::default();
 
     balance.deposit(TxId(1), dec!(10.0))?;
-    balance.withdraw(TxId(2), dec!(7.0))?;
+    balance.withdraw(TxId(2), dec!(7.0), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(3.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, false);
 
-    balance.dispute(TxId(1))?;
+    balance.dispute(TxId(1), None)?;
     assert_eq!(balance.available, dec!(-7.0));
     assert_eq!(balance.held, dec!(10.0));
     assert_eq!(balance.locked, false);
@@ -215,12 +464,12 @@ fn test_dispute_withdrawal() -> Result<(), Error> {
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, false);
 
-    balance.withdraw(TxId(2), dec!(7.0))?;
+    balance.withdraw(TxId(2), dec!(7.0), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(3.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, false);
 
-    balance.dispute(TxId(2))?;
+    balance.dispute(TxId(2), None)?;
     assert_eq!(balance.available, dec!(3.0));
     assert_eq!(balance.held, dec!(-7.0));
     assert_eq!(balance.locked, false);
@@ -245,23 +494,23 @@ fn test_chargeback_deposit() -> Result<(), Error> {
     let mut balance = Balance::default();
 
     balance.deposit(TxId(1), dec!(10.0))?;
-    balance.withdraw(TxId(2), dec!(7.0))?;
+    balance.withdraw(TxId(2), dec!(7.0), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(3.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, false);
 
-    balance.dispute(TxId(1))?;
+    balance.dispute(TxId(1), None)?;
     assert_eq!(balance.available, dec!(-7.0));
     assert_eq!(balance.held, dec!(10.0));
     assert_eq!(balance.locked, false);
 
-    balance.chargeback(TxId(1))?;
+    balance.chargeback(TxId(1), None)?;
     assert_eq!(balance.available, dec!(-7.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, true);
 
     // second chargeback should have no effect
-    balance.chargeback(TxId(1))?;
+    balance.chargeback(TxId(1), None)?;
     assert_eq!(balance.available, dec!(-7.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, true);
@@ -279,23 +528,23 @@ fn test_chargeback_withdrawal() -> Result<(), Error> {
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, false);
 
-    balance.withdraw(TxId(2), dec!(7.0))?;
+    balance.withdraw(TxId(2), dec!(7.0), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(3.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, false);
 
-    balance.dispute(TxId(2))?;
+    balance.dispute(TxId(2), None)?;
     assert_eq!(balance.available, dec!(3.0));
     assert_eq!(balance.held, dec!(-7.0));
     assert_eq!(balance.locked, false);
 
-    balance.chargeback(TxId(2))?;
+    balance.chargeback(TxId(2), None)?;
     assert_eq!(balance.available, dec!(10.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, true);
 
     // second chargeback should have no effect
-    balance.chargeback(TxId(2))?;
+    balance.chargeback(TxId(2), None)?;
     assert_eq!(balance.available, dec!(10.0));
     assert_eq!(balance.held, dec!(0.0));
     assert_eq!(balance.locked, true);
@@ -309,14 +558,14 @@ fn test_deposit_withdraw() -> Result<(), Error> {
     let mut balance = Balance::default();
 
     // try withdraw from empty balance
-    balance.withdraw(TxId(1), dec!(5.00))?;
+    balance.withdraw(TxId(1), dec!(5.00), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(0));
     assert_eq!(balance.held, dec!(0));
     assert_eq!(balance.locked, false);
     assert_eq!(balance.trans.get(&TxId(1)), None);
 
     // try withdraw of zero
-    assert!(balance.withdraw(TxId(2), dec!(0)).is_err());
+    assert!(balance.withdraw(TxId(2), dec!(0), Decimal::ZERO).is_err());
     assert_eq!(balance.held, dec!(0));
     assert_eq!(balance.locked, false);
     assert_eq!(balance.trans.get(&TxId(2)), None);
@@ -338,14 +587,14 @@ fn test_deposit_withdraw() -> Result<(), Error> {
     );
 
     // withdraw more than available
-    balance.withdraw(TxId(5), dec!(11.0))?;
+    balance.withdraw(TxId(5), dec!(11.0), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(10.0));
     assert_eq!(balance.held, dec!(0));
     assert_eq!(balance.locked, false);
     assert_eq!(balance.trans.get(&TxId(5)), None);
 
     // withdraw in bounds
-    balance.withdraw(TxId(6), dec!(3.0))?;
+    balance.withdraw(TxId(6), dec!(3.0), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(7.0));
     assert_eq!(balance.held, dec!(0));
     assert_eq!(balance.locked, false);
@@ -355,7 +604,7 @@ fn test_deposit_withdraw() -> Result<(), Error> {
     );
 
     // withdraw in dupe transaction, check its err
-    assert!(balance.withdraw(TxId(6), dec!(3.0)).is_err());
+    assert!(balance.withdraw(TxId(6), dec!(3.0), Decimal::ZERO).is_err());
     assert_eq!(balance.available, dec!(7.0));
     assert_eq!(balance.held, dec!(0));
     assert_eq!(balance.locked, false);
@@ -377,7 +626,7 @@ fn test_deposit_withdraw() -> Result<(), Error> {
     );
 
     // withdraw all remaining funds
-    balance.withdraw(TxId(7), dec!(7.0))?;
+    balance.withdraw(TxId(7), dec!(7.0), Decimal::ZERO)?;
     assert_eq!(balance.available, dec!(0.0));
     assert_eq!(balance.held, dec!(0));
     assert_eq!(balance.locked, false);
@@ -389,6 +638,111 @@ fn test_deposit_withdraw() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn test_display_overflow() {
+    use rust_decimal_macros::dec;
+    let balance = Balance::from_parts(Decimal::MAX, dec!(1), false, HashMap::new());
+    assert_eq!(balance.total(), None);
+    assert_eq!(
+        balance.to_string(),
+        format!("{},1,OVERFLOW,false", Decimal::MAX)
+    );
+
+    let balance = Balance::from_parts(Decimal::MAX - dec!(1), dec!(1), false, HashMap::new());
+    assert_eq!(balance.total(), Some(Decimal::MAX));
+    assert_eq!(
+        balance.to_string(),
+        format!("{},1,{},false", Decimal::MAX - dec!(1), Decimal::MAX)
+    );
+}
+
+#[test]
+fn test_from_parts() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let trans = HashMap::from([(TxId(7), TranRecord::new(RecordType::Deposit, dec!(2.5)))]);
+    let mut balance = Balance::from_parts(dec!(10), dec!(0), false, trans);
+    balance.dispute(TxId(7), None)?;
+    assert_eq!(balance.available(), dec!(7.5));
+    assert_eq!(balance.held(), dec!(2.5));
+    assert_eq!(balance.total(), Some(dec!(10)));
+
+    balance.chargeback(TxId(7), None)?;
+    assert_eq!(balance.to_string(), "7.5,0.0,7.5,true");
+
+    // a locked balance ignores further activity
+    let mut balance = Balance::from_parts(dec!(1), dec!(0), true, HashMap::new());
+    balance.deposit(TxId(8), dec!(1))?;
+    assert_eq!(balance.available(), dec!(1));
+    Ok(())
+}
+
+#[test]
+fn test_withdraw_minimum() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut balance = Balance::default();
+    balance.deposit(TxId(1), dec!(10))?;
+
+    // would breach the floor, ignored
+    balance.withdraw(TxId(2), dec!(8.5), dec!(2))?;
+    assert_eq!(balance.available(), dec!(10));
+    assert_eq!(balance.record_type(TxId(2)), None);
+
+    // down to exactly the floor is fine
+    balance.withdraw(TxId(3), dec!(8), dec!(2))?;
+    assert_eq!(balance.available(), dec!(2));
+
+    // and with no floor the exact balance can go
+    balance.withdraw(TxId(4), dec!(2), Decimal::ZERO)?;
+    assert_eq!(balance.available(), dec!(0));
+    Ok(())
+}
+
+#[test]
+fn test_max_dispute_cycles() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut balance = Balance::default();
+    balance.deposit(TxId(1), dec!(10))?;
+    for _ in 0..2 {
+        balance.dispute(TxId(1), Some(2))?;
+        assert_eq!(balance.held(), dec!(10));
+        balance.resolve(TxId(1))?;
+        assert_eq!(balance.available(), dec!(10));
+    }
+
+    // the third dispute is over the cap, ignored
+    balance.dispute(TxId(1), Some(2))?;
+    assert_eq!(balance.held(), dec!(0));
+    assert_eq!(balance.available(), dec!(10));
+
+    // with no cap it can go round again
+    balance.dispute(TxId(1), None)?;
+    assert_eq!(balance.held(), dec!(10));
+    Ok(())
+}
+
+#[test]
+fn test_mint_burn() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut balance = Balance::default();
+    balance.mint(dec!(5))?;
+    balance.burn(dec!(2))?;
+    assert_eq!(balance.available(), dec!(3));
+    assert!(balance.trans.is_empty());
+
+    // can't burn below zero
+    assert!(balance.burn(dec!(3.0001)).is_err());
+    assert_eq!(balance.available(), dec!(3));
+
+    // nothing recorded, so nothing to dispute
+    balance.dispute(TxId(1), None)?;
+    assert_eq!(balance.held(), dec!(0));
+    Ok(())
+}
+
 // #[test]
 // fn test_sizeof() {
 //     // Uncomment this to get estimate of transaction storage cost
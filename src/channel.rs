@@ -0,0 +1,78 @@
+use anyhow::Error;
+use clap::ValueEnum;
+use tokio::sync::mpsc;
+
+use crate::transaction::Transaction;
+
+/// Which kind of queue feeds each shard
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ChannelKind {
+    /// Reader waits when a shard falls behind, bounding memory
+    #[default]
+    Bounded,
+    /// Reader never waits, queues grow as needed
+    Unbounded,
+}
+
+/// Sending half of a shard queue
+pub enum ShardSender {
+    Bounded(mpsc::Sender<Transaction>),
+    Unbounded(mpsc::UnboundedSender<Transaction>),
+}
+
+/// Receiving half of a shard queue
+pub enum ShardReceiver {
+    Bounded(mpsc::Receiver<Transaction>),
+    Unbounded(mpsc::UnboundedReceiver<Transaction>),
+}
+
+/// Create a shard queue, `bound` only applies to bounded queues
+pub fn channel(kind: ChannelKind, bound: usize) -> (ShardSender, ShardReceiver) {
+    match kind {
+        ChannelKind::Bounded => {
+            let (tx, rx) = mpsc::channel(bound);
+            (ShardSender::Bounded(tx), ShardReceiver::Bounded(rx))
+        }
+        ChannelKind::Unbounded => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (ShardSender::Unbounded(tx), ShardReceiver::Unbounded(rx))
+        }
+    }
+}
+
+impl ShardSender {
+    pub async fn send(&self, t: Transaction) -> Result<(), Error> {
+        match self {
+            ShardSender::Bounded(tx) => tx.send(t).await?,
+            ShardSender::Unbounded(tx) => tx.send(t)?,
+        }
+        Ok(())
+    }
+}
+
+impl ShardReceiver {
+    pub async fn recv(&mut self) -> Option<Transaction> {
+        match self {
+            ShardReceiver::Bounded(rx) => rx.recv().await,
+            ShardReceiver::Unbounded(rx) => rx.recv().await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_channel_kinds() -> Result<(), Error> {
+    use crate::ids::{ClientId, TxId};
+    use crate::transaction::TranType;
+
+    for kind in [ChannelKind::Bounded, ChannelKind::Unbounded] {
+        let (tx, mut rx) = channel(kind, 2);
+        let t = Transaction::new(TranType::Dispute, ClientId(1), TxId(1), None);
+        tx.send(t.clone()).await?;
+        tx.send(t.clone()).await?;
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(t.clone()));
+        assert_eq!(rx.recv().await, Some(t));
+        assert_eq!(rx.recv().await, None);
+    }
+    Ok(())
+}
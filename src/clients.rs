@@ -1,32 +1,207 @@
-use anyhow::{bail, Error};
+use anyhow::{anyhow, bail, Error};
+use clap::ValueEnum;
+use rust_decimal::Decimal;
 
+#[cfg(feature = "btree")]
+use std::collections::btree_map::Entry;
+#[cfg(not(feature = "btree"))]
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::io::Write;
 
-use crate::balance::Balance;
-use crate::ids::ClientId;
+use crate::balance::{Balance, OverLimit, Policy, RecordType};
+use crate::ids::{AccountId, AssetId, ClientId, TxId};
+use crate::output::{quote_field, write_header, write_row, OutputOptions, SortBy};
+use crate::release::AutoRelease;
 use crate::transaction::{TranType, Transaction};
 
+/// Map from client and asset to balance. HashMap by default, or BTreeMap with
+/// the `btree` feature which keeps clients sorted so output needs no sort.
+#[cfg(not(feature = "btree"))]
+pub type Ledger = HashMap<AccountId, Balance>;
+#[cfg(feature = "btree")]
+pub type Ledger = BTreeMap<AccountId, Balance>;
+
+/// A ledger with room for this many clients before it grows. A BTreeMap
+/// allocates per node so can't be pre-sized.
+#[cfg(not(feature = "btree"))]
+fn ledger_with_capacity(clients: usize) -> Ledger {
+    Ledger::with_capacity(clients)
+}
+#[cfg(feature = "btree")]
+fn ledger_with_capacity(_clients: usize) -> Ledger {
+    Ledger::new()
+}
+
+/// How to combine client sets that may contain the same client
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum CombineMode {
+    /// The same client appearing twice is an error
+    #[default]
+    Strict,
+    /// Balances for the same client are summed, erroring only on a reused tx id
+    Merge,
+}
+
+/// What `Clients::merge_from` did with the other side's clients
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    /// Clients only on the other side, moved across as they were
+    pub added: usize,
+    /// Clients on both sides whose balances were summed
+    pub merged: usize,
+    /// Clients on both sides that couldn't be combined, e.g. a reused tx id
+    pub conflicts: Vec<Error>,
+}
+
+/// A chargeback locked an account under `Policy::fail_on_lock`
+#[derive(Debug)]
+pub struct AccountLocked {
+    pub client: ClientId,
+    pub tx: TxId,
+}
+
+impl Display for AccountLocked {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Client {} locked by chargeback of tx {}",
+            self.client.id(),
+            self.tx.id()
+        )
+    }
+}
+
+impl std::error::Error for AccountLocked {}
+
+/// What to do with disputes still open once all the input is processed
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum FinalizeMode {
+    /// Leave them open, the funds stay held
+    #[default]
+    Leave,
+    /// Resolve them, returning the funds to available
+    Resolve,
+    /// Charge them back, locking the account
+    Chargeback,
+}
+
+/// A dispute neither resolved nor charged back
+#[derive(Debug, Eq, PartialEq)]
+pub struct OpenDispute {
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Decimal,
+    /// How many of the client's transactions came after the dispute
+    pub age: u32,
+}
+
+/// One asset's balances summed across all clients
+#[derive(Debug, Eq, PartialEq)]
+pub struct CurrencyTotal {
+    pub asset: AssetId,
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+/// A transaction `Clients::apply` has applied, for `Clients::after_apply`
+#[derive(Debug)]
+pub(crate) struct Applied {
+    account: AccountId,
+    tx: TxId,
+    tran_type: TranType,
+    was_locked: bool,
+    /// The transaction raised a dispute, for `Policy::auto_release_after`
+    raised: bool,
+}
+
 /// Represents a collection of clients and allows us to process a transaction
 #[derive(Debug, Default)]
 pub struct Clients {
-    pub balance_map: HashMap<ClientId, Balance>,
+    pub balance_map: Ledger,
+    pub policy: Policy,
+    /// Every client referenced, if `Policy::track_appeared`
+    appeared: HashSet<ClientId>,
+    /// Transactions seen per client, if `Policy::track_disputes`
+    client_txs: HashMap<ClientId, u32>,
+    /// The client's transaction count when each open dispute was raised
+    disputed_at: HashMap<(ClientId, TxId), u32>,
+    /// Disputes to resolve, if `Policy::auto_release_after`
+    release: Option<AutoRelease>,
 }
 
 impl Clients {
+    pub fn new(policy: Policy) -> Self {
+        Self::with_capacity(policy, 0)
+    }
+
+    /// Pre-size for the expected number of clients, saving rehashing as they arrive
+    pub fn with_capacity(policy: Policy, clients: usize) -> Self {
+        Self {
+            balance_map: ledger_with_capacity(clients),
+            appeared: HashSet::new(),
+            client_txs: HashMap::new(),
+            disputed_at: HashMap::new(),
+            release: policy.auto_release_after.map(AutoRelease::new),
+            policy,
+        }
+    }
+
     pub fn process(&mut self, t: Transaction) -> Result<(), Error> {
-        let e = self.balance_map.entry(t.client);
-        match (t.tran_type, e, t.amount) {
+        let applied = self.apply(t)?;
+        self.after_apply(applied)
+    }
+
+    /// Apply the transaction, or ignore it under the rules. An error leaves
+    /// everything as it was. Follow with `after_apply`.
+    pub(crate) fn apply(&mut self, t: Transaction) -> Result<Applied, Error> {
+        if self.policy.strict_tx_semantics {
+            self.check_tx_semantics(&t)?;
+        }
+        let tx = t.tx;
+        let account = t.account();
+        let was_locked = self.is_locked(&account);
+        let applied = |raised| Applied {
+            account: account.clone(),
+            tx,
+            tran_type: t.tran_type,
+            was_locked,
+            raised,
+        };
+        // a locked account ignores the row anyway, so its limits don't apply
+        if !was_locked && self.over_limit(&t)? {
+            self.track(&account, tx, t.tran_type);
+            return Ok(applied(false));
+        }
+        let was_disputed = self.release.is_some()
+            && t.tran_type == TranType::Dispute
+            && self.is_disputed(&account, tx);
+        let existed = self.balance_map.contains_key(&account);
+        let e = self.balance_map.entry(account.clone());
+        let result = match (t.tran_type, e, t.amount) {
             (TranType::Deposit, e, Some(amount)) => e.or_default().deposit(t.tx, amount),
-            (TranType::Withdrawal, e, Some(amount)) => e.or_default().withdraw(t.tx, amount),
-            (TranType::Deposit, _, None) | (TranType::Withdrawal, _, None) => {
+            (TranType::Withdrawal, e, Some(amount)) => {
+                e.or_default()
+                    .withdraw(t.tx, amount, self.policy.keep_minimum)
+            }
+            (TranType::Mint, e, Some(amount)) => e.or_default().mint(amount),
+            (TranType::Burn, e, Some(amount)) => e.or_default().burn(amount),
+            (
+                TranType::Deposit | TranType::Withdrawal | TranType::Mint | TranType::Burn,
+                _,
+                None,
+            ) => {
                 bail!("Invalid transaction, missing amount for {:?}", t)
             }
 
-            (TranType::Dispute, Entry::Occupied(mut e), _) => e.get_mut().dispute(t.tx),
+            (TranType::Dispute, Entry::Occupied(mut e), _) => {
+                e.get_mut().dispute(t.tx, self.policy.max_dispute_cycles)
+            }
             (TranType::Resolve, Entry::Occupied(mut e), _) => e.get_mut().resolve(t.tx),
-            (TranType::Chargeback, Entry::Occupied(mut e), _) => e.get_mut().chargeback(t.tx),
+            (TranType::Chargeback, Entry::Occupied(mut e), _) => {
+                e.get_mut().chargeback(t.tx, t.reason)
+            }
 
             // partner error, the client for dispute doesn't exist, ignore
             (
@@ -36,31 +211,473 @@ impl Clients {
             ) => Ok(()),
 
             (_, _, Some(_)) => bail!("Invalid transaction, was not expeciting amount for {:?}", t),
+        };
+        if let Err(e) = result {
+            // don't leave behind the empty balance made for a new client
+            if !existed {
+                self.balance_map.remove(&account);
+            }
+            return Err(e);
         }
+        let raised = self.release.is_some()
+            && t.tran_type == TranType::Dispute
+            && !was_disputed
+            && self.is_disputed(&account, tx);
+        self.track(&account, tx, t.tran_type);
+        Ok(applied(raised))
     }
 
-    pub fn combine(&mut self, other: Clients) -> Result<(), Error> {
-        for (client, balance) in other.balance_map {
-            let e = self.balance_map.entry(client);
-            match e {
-                Entry::Occupied(_) => bail!("client shards should not overlap"),
-                Entry::Vacant(e) => {
-                    e.insert(balance);
+    /// Follow up a transaction `apply` returned: age and release disputes,
+    /// then the checks that stop a run. An error here comes after the
+    /// transaction has taken effect.
+    pub(crate) fn after_apply(&mut self, applied: Applied) -> Result<(), Error> {
+        let Applied {
+            account,
+            tx,
+            tran_type,
+            was_locked,
+            raised,
+        } = applied;
+        let client = account.0;
+        if let Some(release) = &mut self.release {
+            release.tick(client);
+            if raised {
+                release.raised(client, account.1.clone(), tx);
+            }
+        }
+        self.release_due(client)?;
+        if let (TranType::Dispute, Some(limit)) = (tran_type, self.policy.held_ratio_alert) {
+            if let Some(alert) = self.held_ratio_alert(&account, limit) {
+                eprintln!("Warning: {}", alert);
+            }
+        }
+        if let Some(max_dp) = self.policy.paranoid_max_dp {
+            self.check_held_dp(&account, max_dp)?;
+        }
+        if self.policy.fail_on_lock && !was_locked && self.is_locked(&account) {
+            return Err(AccountLocked { client, tx }.into());
+        }
+        Ok(())
+    }
+
+    /// Note the client for `Policy::track_appeared` and `Policy::track_disputes`
+    fn track(&mut self, account: &AccountId, tx: TxId, tran_type: TranType) {
+        if self.policy.track_appeared {
+            self.appeared.insert(account.0);
+        }
+        if self.policy.track_disputes {
+            self.track_dispute(account, tx, tran_type);
+        }
+    }
+
+    /// A risk warning if more than `limit` of the client's funds in the
+    /// asset are held
+    fn held_ratio_alert(&self, account: &AccountId, limit: Decimal) -> Option<String> {
+        let ratio = self.balance_map.get(account)?.held_ratio()?;
+        (ratio > limit).then(|| {
+            format!(
+                "client {} has {} of its funds held, over {}",
+                account.0.id(),
+                ratio.round_dp(4),
+                limit
+            )
+        })
+    }
+
+    /// Count the client's transaction, noting when a dispute was raised
+    fn track_dispute(&mut self, account: &AccountId, tx: TxId, tran_type: TranType) {
+        let client = account.0;
+        let disputed = self.is_disputed(account, tx);
+        let count = self.client_txs.entry(client).or_default();
+        *count += 1;
+        if !disputed {
+            self.disputed_at.remove(&(client, tx));
+        } else if tran_type == TranType::Dispute {
+            self.disputed_at.entry((client, tx)).or_insert(*count);
+        }
+    }
+
+    /// Every dispute still open, by client then tx id. Ages need
+    /// `Policy::track_disputes`, otherwise they are 0.
+    pub fn open_dispute_report(&self) -> Vec<OpenDispute> {
+        let mut open = Vec::new();
+        for ((client, _), balance) in self.iter_sorted() {
+            for tx in balance.open_disputes() {
+                let age = match (
+                    self.client_txs.get(client),
+                    self.disputed_at.get(&(*client, tx)),
+                ) {
+                    (Some(count), Some(at)) => count - at,
+                    _ => 0,
+                };
+                open.push(OpenDispute {
+                    client: *client,
+                    tx,
+                    amount: balance.disputed_amount(tx).unwrap_or_default(),
+                    age,
+                });
+            }
+        }
+        open
+    }
+
+    /// Resolve the client's disputes that have waited out
+    /// `Policy::auto_release_after`, unless charged back or resolved already
+    fn release_due(&mut self, client: ClientId) -> Result<(), Error> {
+        let due = match &mut self.release {
+            Some(release) => release.due(client),
+            None => return Ok(()),
+        };
+        for (asset, tx) in due {
+            if let Some(balance) = self.balance_map.get_mut(&(client, asset)) {
+                balance.resolve(tx)?;
+                self.disputed_at.remove(&(client, tx));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_disputed(&self, account: &AccountId, tx: TxId) -> bool {
+        matches!(self.balance_map.get(account), Some(b) if b.disputed_amount(tx).is_some())
+    }
+
+    fn is_locked(&self, account: &AccountId) -> bool {
+        matches!(self.balance_map.get(account), Some(balance) if balance.locked())
+    }
+
+    /// Whether a deposit or withdrawal is over its limit and to be ignored,
+    /// or an error if over limits are errors
+    fn over_limit(&self, t: &Transaction) -> Result<bool, Error> {
+        let limit = match t.tran_type {
+            TranType::Deposit => self.policy.max_deposit,
+            TranType::Withdrawal => self.policy.max_withdrawal,
+            _ => None,
+        };
+        match (limit, t.amount) {
+            (Some(limit), Some(amount)) if amount > limit => match self.policy.over_limit {
+                OverLimit::Error => bail!(
+                    "{} of {} for client {} is over the {} limit of {}",
+                    t.tran_type.name(),
+                    amount,
+                    t.client.id(),
+                    t.tran_type.name(),
+                    limit
+                ),
+                OverLimit::Ignore => Ok(true),
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /// Error if held has picked up more precision than any input amount may have
+    fn check_held_dp(&self, account: &AccountId, max_dp: u32) -> Result<(), Error> {
+        if let Some(balance) = self.balance_map.get(account) {
+            if balance.held().scale() > max_dp {
+                bail!(
+                    "held {} for client {} has more than {} dp",
+                    balance.held(),
+                    account.0.id(),
+                    max_dp
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Error if a dispute, resolve or chargeback refers to a withdrawal
+    fn check_tx_semantics(&self, t: &Transaction) -> Result<(), Error> {
+        match t.tran_type {
+            TranType::Dispute | TranType::Resolve | TranType::Chargeback => {
+                let rec_type = self
+                    .balance_map
+                    .get(&t.account())
+                    .and_then(|balance| balance.record_type(t.tx));
+                if rec_type == Some(RecordType::Withdrawal) {
+                    bail!(
+                        "{} of withdrawal {} for client {}, only deposits may be disputed",
+                        t.tran_type.name(),
+                        t.tx.id(),
+                        t.client.id()
+                    );
+                }
+            }
+            TranType::Deposit | TranType::Withdrawal | TranType::Mint | TranType::Burn => (),
+        }
+        Ok(())
+    }
+
+    /// Iterate the clients, then each client's assets, in a stable order so
+    /// we can compare test data
+    #[cfg(not(feature = "btree"))]
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (&AccountId, &Balance)> {
+        let mut sorted: Vec<_> = self.balance_map.iter().collect();
+        sorted.sort_by_key(|(account, _)| *account);
+        sorted.into_iter()
+    }
+
+    /// Iterate the clients in a stable order, the BTreeMap is already sorted
+    #[cfg(feature = "btree")]
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (&AccountId, &Balance)> {
+        self.balance_map.iter()
+    }
+
+    /// Whether any balance is for an asset other than the implicit one, in
+    /// which case the output gains an asset column
+    pub fn has_assets(&self) -> bool {
+        self.balance_map
+            .keys()
+            .any(|(_, asset)| !asset.is_implicit())
+    }
+
+    /// Clients in output order, by id or the `sort_by` column, and with
+    /// `group_by_lock` the locked ones first then the rest in the same order
+    pub fn iter_output(
+        &self,
+        opts: &OutputOptions,
+    ) -> impl Iterator<Item = (&AccountId, &Balance)> {
+        let mut sorted: Vec<_> = self.iter_sorted().collect();
+        if opts.sort_by != SortBy::Id || opts.desc {
+            // stable, so ties stay in client id order
+            sorted.sort_by(|(a_id, a), (b_id, b)| {
+                let ord = match opts.sort_by {
+                    SortBy::Id => a_id.cmp(b_id),
+                    SortBy::Total => a.total().cmp(&b.total()),
+                    SortBy::Available => a.available().cmp(&b.available()),
+                    SortBy::Held => a.held().cmp(&b.held()),
+                };
+                if opts.desc {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+        }
+        let (locked, unlocked): (Vec<_>, Vec<_>) = sorted
+            .into_iter()
+            .partition(|(_, balance)| opts.group_by_lock && balance.locked());
+        locked.into_iter().chain(unlocked)
+    }
+
+    /// Write the header and client rows as CSV
+    pub fn write_to<W: Write>(&self, w: &mut W, opts: &OutputOptions) -> Result<(), Error> {
+        let assets = self.has_assets();
+        if opts.output_scale.is_some() {
+            // an amount that isn't a whole unit errors before any row is written
+            self.write_rows(&mut std::io::sink(), opts, assets)?;
+        }
+        write_header(w, opts, assets)?;
+        self.write_rows(w, opts, assets)
+    }
+
+    /// Write the clients as CSV in the default format, sorted by client id
+    pub fn write_csv<W: Write>(&self, mut w: W) -> Result<(), Error> {
+        self.write_to(&mut w, &OutputOptions::default())
+    }
+
+    /// Write every recorded deposit and withdrawal as CSV, by client and then
+    /// tx id, with an asset column after the client if any balance has one
+    pub fn write_records<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let assets = self.has_assets();
+        if assets {
+            writeln!(w, "client,asset,tx,type,amount,disputed")?;
+        } else {
+            writeln!(w, "client,tx,type,amount,disputed")?;
+        }
+        for ((client, asset), balance) in self.iter_sorted() {
+            for (tx, record) in balance.records() {
+                write!(w, "{}", client.id())?;
+                if assets {
+                    write!(w, ",{}", quote_field(&asset.0))?;
+                }
+                writeln!(
+                    w,
+                    ",{},{},{},{}",
+                    tx.id(),
+                    record.rec_type().name(),
+                    record.amount(),
+                    record.disputed()
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write just the client rows as CSV, limited to the `head` or `tail` rows
+    /// if set, with an asset column if `assets`
+    pub fn write_rows<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &OutputOptions,
+        assets: bool,
+    ) -> Result<(), Error> {
+        let skip = match opts.tail {
+            Some(tail) => self.balance_map.len().saturating_sub(tail),
+            None => 0,
+        };
+        let rows = self
+            .iter_output(opts)
+            .skip(skip)
+            .take(opts.head.unwrap_or(usize::MAX));
+        for (account, balance) in rows {
+            write_row(w, account, balance, opts, assets)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the terminal action to every open dispute, in tx id order per
+    /// client. As with input chargebacks, once an account is locked its
+    /// remaining disputes are left open.
+    pub fn finalize_disputes(&mut self, mode: FinalizeMode) -> Result<(), Error> {
+        if mode == FinalizeMode::Leave {
+            return Ok(());
+        }
+        for balance in self.balance_map.values_mut() {
+            for tx in balance.open_disputes() {
+                match mode {
+                    FinalizeMode::Leave => (),
+                    FinalizeMode::Resolve => balance.resolve(tx)?,
+                    FinalizeMode::Chargeback => balance.chargeback(tx, None)?,
                 }
             }
         }
         Ok(())
     }
+
+    pub fn combine(&mut self, other: Clients, mode: CombineMode) -> Result<(), Error> {
+        match self.merge_from(other, mode).conflicts.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Clients that appeared in the input but have nothing recorded and a
+    /// zero balance in every asset, or no balance at all, sorted. Needs
+    /// `Policy::track_appeared`.
+    pub fn empty_clients(&self) -> Vec<ClientId> {
+        let used: HashSet<ClientId> = self
+            .balance_map
+            .iter()
+            .filter(|(_, balance)| !balance.is_empty())
+            .map(|((client, _), _)| *client)
+            .collect();
+        let mut empty: Vec<ClientId> = self
+            .appeared
+            .iter()
+            .filter(|client| !used.contains(client))
+            .copied()
+            .collect();
+        empty.sort_by_key(|client| client.id());
+        empty
+    }
+
+    /// Available and held summed over the clients for each asset, sorted by
+    /// asset
+    pub fn currency_totals(&self) -> Result<Vec<CurrencyTotal>, Error> {
+        let mut totals: BTreeMap<&AssetId, (Decimal, Decimal)> = BTreeMap::new();
+        for ((_, asset), balance) in &self.balance_map {
+            let overflow = || anyhow!("Totalling {} overflowed", asset.0);
+            let total = totals.entry(asset).or_default();
+            total.0 = total
+                .0
+                .checked_add(balance.available())
+                .ok_or_else(overflow)?;
+            total.1 = total.1.checked_add(balance.held()).ok_or_else(overflow)?;
+        }
+        Ok(totals
+            .into_iter()
+            .map(|(asset, (available, held))| CurrencyTotal {
+                asset: asset.clone(),
+                available,
+                held,
+            })
+            .collect())
+    }
+
+    /// Give every client a zero balance in each of `currencies` it has no
+    /// balance in, so the output has a row for each client and currency
+    pub fn fill_currencies(&mut self, currencies: &[AssetId]) {
+        let clients: HashSet<ClientId> =
+            self.balance_map.keys().map(|(client, _)| *client).collect();
+        for client in clients {
+            for currency in currencies {
+                self.balance_map
+                    .entry((client, currency.clone()))
+                    .or_default();
+            }
+        }
+    }
+
+    /// Combine as `combine`, but carry on past conflicts and report what
+    /// happened. A conflicting client keeps this side's balance unchanged.
+    pub fn merge_from(&mut self, other: Clients, mode: CombineMode) -> MergeReport {
+        let mut report = MergeReport::default();
+        self.appeared.extend(other.appeared);
+        self.disputed_at.extend(other.disputed_at);
+        for (client, count) in other.client_txs {
+            *self.client_txs.entry(client).or_default() += count;
+        }
+        for (account, balance) in other.balance_map {
+            let e = self.balance_map.entry(account.clone());
+            let merged = match (e, mode) {
+                (Entry::Occupied(_), CombineMode::Strict) => {
+                    Err(anyhow!("client shards should not overlap"))
+                }
+                (Entry::Occupied(mut e), CombineMode::Merge) => e.get_mut().merge(balance),
+                (Entry::Vacant(e), _) => {
+                    e.insert(balance);
+                    report.added += 1;
+                    continue;
+                }
+            };
+            match merged {
+                Ok(()) => report.merged += 1,
+                Err(e) => report
+                    .conflicts
+                    .push(e.context(format!("Merging client {}", account.0.id()))),
+            }
+        }
+        report
+    }
+}
+
+/// Build a test transaction without an asset
+#[cfg(test)]
+pub(crate) fn tran(
+    tran_type: TranType,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
+) -> Transaction {
+    Transaction::new(tran_type, ClientId(client), TxId(tx), amount)
+}
+
+/// Feed transactions through in order as the CSV loop does, including the
+/// tx id reuse check, stopping at the first error with its index. The reuse
+/// check only spans this one call.
+#[cfg(test)]
+pub(crate) fn process_all(clients: &mut Clients, ts: &[Transaction]) -> Result<(), Error> {
+    use crate::dedup::SeenTx;
+    use anyhow::Context;
+
+    let mut seen_tx = SeenTx::default();
+    for (i, t) in ts.iter().enumerate() {
+        seen_tx
+            .check(t)
+            .and_then(|_| clients.process(t.clone()))
+            .with_context(|| format!("Transaction {}", i))?;
+    }
+    Ok(())
 }
 
 impl Display for Clients {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // get a stable order for the clients so we can compare test data
-        let mut keys: Vec<ClientId> = self.balance_map.keys().cloned().collect();
-        keys.sort();
-        for client in keys {
-            let balance = self.balance_map.get(&client).unwrap();
-            writeln!(f, "{},{}", client.id(), balance)?
+        let assets = self.has_assets();
+        for ((client, asset), balance) in self.iter_sorted() {
+            if assets {
+                writeln!(f, "{},{},{}", client.id(), asset.0, balance)?
+            } else {
+                writeln!(f, "{},{}", client.id(), balance)?
+            }
         }
         Ok(())
     }
@@ -68,7 +685,6 @@ impl Display for Clients {
 
 #[test]
 fn test_process() -> Result<(), Error> {
-    use crate::ids::TxId;
     use rust_decimal_macros::dec;
 
     let mut clients = Clients::default();
@@ -78,28 +694,40 @@ fn test_process() -> Result<(), Error> {
         TxId(1),
         Some(dec!(1.00)),
     ))?;
-    assert!(clients.balance_map.get(&ClientId(1)).is_some());
+    assert!(clients
+        .balance_map
+        .contains_key(&(ClientId(1), AssetId::default())));
 
     let t = Transaction::new(TranType::Deposit, ClientId(2), TxId(2), Some(dec!(1.00)));
     clients.process(t)?;
-    assert!(clients.balance_map.get(&ClientId(2)).is_some());
+    assert!(clients
+        .balance_map
+        .contains_key(&(ClientId(2), AssetId::default())));
 
     let t = Transaction::new(TranType::Withdrawal, ClientId(2), TxId(3), Some(dec!(1.00)));
     clients.process(t)?;
-    assert!(clients.balance_map.get(&ClientId(2)).is_some());
+    assert!(clients
+        .balance_map
+        .contains_key(&(ClientId(2), AssetId::default())));
 
     // Unknown client cases. partner error, ignore and check no client record is created
     let t = Transaction::new(TranType::Dispute, ClientId(99), TxId(2), None);
     assert!(clients.process(t).is_ok());
-    assert!(clients.balance_map.get(&ClientId(99)).is_none());
+    assert!(!clients
+        .balance_map
+        .contains_key(&(ClientId(99), AssetId::default())));
 
     let t = Transaction::new(TranType::Resolve, ClientId(99), TxId(2), None);
     assert!(clients.process(t).is_ok());
-    assert!(clients.balance_map.get(&ClientId(99)).is_none());
+    assert!(!clients
+        .balance_map
+        .contains_key(&(ClientId(99), AssetId::default())));
 
     let t = Transaction::new(TranType::Chargeback, ClientId(99), TxId(2), None);
     assert!(clients.process(t).is_ok());
-    assert!(clients.balance_map.get(&ClientId(99)).is_none());
+    assert!(!clients
+        .balance_map
+        .contains_key(&(ClientId(99), AssetId::default())));
 
     let d = clients.to_string();
     let expected = "1,1.00,0,1.00,false
@@ -109,3 +737,952 @@ fn test_process() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_lock_reason() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::default();
+    let reason = Some("fraud, card stolen".to_string());
+    process_all(
+        &mut clients,
+        &[
+            Transaction::new(TranType::Deposit, ClientId(1), TxId(1), Some(dec!(5.0))),
+            Transaction::new(TranType::Deposit, ClientId(2), TxId(2), Some(dec!(3.0))),
+            Transaction::new(TranType::Dispute, ClientId(1), TxId(1), None),
+            Transaction::new(TranType::Dispute, ClientId(2), TxId(2), None),
+            Transaction::new(TranType::Chargeback, ClientId(1), TxId(1), None).with_reason(reason),
+            Transaction::new(TranType::Chargeback, ClientId(2), TxId(2), None),
+        ],
+    )?;
+
+    let balance = &clients.balance_map[&(ClientId(1), AssetId::default())];
+    assert_eq!(balance.lock_reason(), Some("fraud, card stolen"));
+    assert_eq!(
+        clients.balance_map[&(ClientId(2), AssetId::default())].lock_reason(),
+        None
+    );
+
+    let mut out = Vec::new();
+    clients.write_to(&mut out, &OutputOptions::default())?;
+    let expected = "client,available,held,total,locked
+1,0.0,0.0,0.0,true
+2,0.0,0.0,0.0,true
+";
+    assert_eq!(String::from_utf8(out)?, expected);
+
+    let mut out = Vec::new();
+    let opts = OutputOptions {
+        lock_reasons: true,
+        ..Default::default()
+    };
+    clients.write_to(&mut out, &opts)?;
+    let expected = "client,available,held,total,locked,lock_reason
+1,0.0,0.0,0.0,true,\"fraud, card stolen\"
+2,0.0,0.0,0.0,true,
+";
+    assert_eq!(String::from_utf8(out)?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_output_scale_checked_first() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    // client 2's sub cent amount fails the whole output, not just its row
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(1.5))),
+            tran(TranType::Deposit, 2, 2, Some(dec!(0.001))),
+        ],
+    )?;
+    let opts = OutputOptions {
+        output_scale: Some(dec!(0.01)),
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    assert!(clients.write_to(&mut out, &opts).is_err());
+    assert!(out.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_combine() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    // disjoint clients combine the same in both modes
+    for mode in [CombineMode::Strict, CombineMode::Merge] {
+        let mut a = Clients::default();
+        a.process(tran(TranType::Deposit, 1, 1, Some(dec!(1.0))))?;
+        let mut b = Clients::default();
+        b.process(tran(TranType::Deposit, 2, 2, Some(dec!(2.0))))?;
+        a.combine(b, mode)?;
+        assert_eq!(a.to_string(), "1,1.0,0,1.0,false\n2,2.0,0,2.0,false\n");
+    }
+
+    // overlapping clients
+    let overlapping = || -> Result<(Clients, Clients), Error> {
+        let mut a = Clients::default();
+        a.process(tran(TranType::Deposit, 1, 1, Some(dec!(1.0))))?;
+        a.process(tran(TranType::Deposit, 2, 2, Some(dec!(2.0))))?;
+        let mut b = Clients::default();
+        b.process(tran(TranType::Deposit, 1, 3, Some(dec!(3.0))))?;
+        b.process(tran(TranType::Dispute, 1, 3, None))?;
+        Ok((a, b))
+    };
+
+    let (mut a, b) = overlapping()?;
+    assert!(a.combine(b, CombineMode::Strict).is_err());
+
+    let (mut a, b) = overlapping()?;
+    a.combine(b, CombineMode::Merge)?;
+    assert_eq!(a.to_string(), "1,1.0,3.0,4.0,false\n2,2.0,0,2.0,false\n");
+    // the union of records is disputable, tx 3 came from the other side
+    a.process(tran(TranType::Resolve, 1, 3, None))?;
+    assert_eq!(a.to_string(), "1,4.0,0.0,4.0,false\n2,2.0,0,2.0,false\n");
+
+    // the same tx id on both sides is a conflict
+    let mut a = Clients::default();
+    a.process(tran(TranType::Deposit, 1, 1, Some(dec!(1.0))))?;
+    let mut b = Clients::default();
+    b.process(tran(TranType::Deposit, 1, 1, Some(dec!(1.0))))?;
+    assert!(a.combine(b, CombineMode::Merge).is_err());
+    assert_eq!(a.to_string(), "1,1.0,0,1.0,false\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_merge_from() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let sides = || -> Result<(Clients, Clients), Error> {
+        let mut a = Clients::default();
+        a.process(tran(TranType::Deposit, 1, 1, Some(dec!(1.0))))?;
+        a.process(tran(TranType::Deposit, 2, 2, Some(dec!(2.0))))?;
+        let mut b = Clients::default();
+        b.process(tran(TranType::Deposit, 1, 3, Some(dec!(3.0))))?;
+        // conflicts with a's tx 2
+        b.process(tran(TranType::Deposit, 2, 2, Some(dec!(5.0))))?;
+        b.process(tran(TranType::Deposit, 3, 4, Some(dec!(4.0))))?;
+        b.process(tran(TranType::Deposit, 4, 5, Some(dec!(5.0))))?;
+        Ok((a, b))
+    };
+
+    let (mut a, b) = sides()?;
+    let report = a.merge_from(b, CombineMode::Merge);
+    assert_eq!((report.added, report.merged), (2, 1));
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(
+        format!("{:#}", report.conflicts[0]),
+        "Merging client 2: Conflicting transaction 2"
+    );
+    // the conflicting client is left as it was
+    assert_eq!(
+        a.to_string(),
+        "1,4.0,0,4.0,false\n2,2.0,0,2.0,false\n3,4.0,0,4.0,false\n4,5.0,0,5.0,false\n"
+    );
+
+    let (mut a, b) = sides()?;
+    let report = a.merge_from(b, CombineMode::Strict);
+    assert_eq!((report.added, report.merged), (2, 0));
+    assert_eq!(report.conflicts.len(), 2);
+
+    let (mut a, b) = sides()?;
+    assert!(a.combine(b, CombineMode::Merge).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_strict_tx_semantics() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let scenario = |clients: &mut Clients| -> Result<(), Error> {
+        process_all(
+            clients,
+            &[
+                Transaction::new(TranType::Deposit, ClientId(1), TxId(1), Some(dec!(10.0))),
+                Transaction::new(TranType::Withdrawal, ClientId(1), TxId(2), Some(dec!(4.0))),
+            ],
+        )
+    };
+    let strict = Policy {
+        strict_tx_semantics: true,
+        ..Default::default()
+    };
+
+    // dispute of a deposit acts the same either way
+    let mut clients = Clients::new(strict.clone());
+    scenario(&mut clients)?;
+    clients.process(Transaction::new(
+        TranType::Dispute,
+        ClientId(1),
+        TxId(1),
+        None,
+    ))?;
+    assert_eq!(clients.to_string(), "1,-4.0,10.0,6.0,false\n");
+
+    // dispute of a withdrawal is an error under strict, leaving balances alone
+    let mut clients = Clients::new(strict);
+    scenario(&mut clients)?;
+    let err = clients
+        .process(Transaction::new(
+            TranType::Dispute,
+            ClientId(1),
+            TxId(2),
+            None,
+        ))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "dispute of withdrawal 2 for client 1, only deposits may be disputed"
+    );
+    assert_eq!(clients.to_string(), "1,6.0,0,6.0,false\n");
+
+    // and acted on by default
+    let mut clients = Clients::default();
+    scenario(&mut clients)?;
+    clients.process(Transaction::new(
+        TranType::Dispute,
+        ClientId(1),
+        TxId(2),
+        None,
+    ))?;
+    assert_eq!(clients.to_string(), "1,6.0,-4.0,2.0,false\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_finalize_disputes() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let open_dispute = || -> Result<Clients, Error> {
+        let mut clients = Clients::default();
+        for (tran_type, tx, amount) in [
+            (TranType::Deposit, 1, Some(dec!(5.0))),
+            (TranType::Deposit, 2, Some(dec!(2.0))),
+            (TranType::Dispute, 2, None),
+        ] {
+            clients.process(Transaction::new(tran_type, ClientId(1), TxId(tx), amount))?;
+        }
+        Ok(clients)
+    };
+
+    let mut clients = open_dispute()?;
+    clients.finalize_disputes(FinalizeMode::Leave)?;
+    assert_eq!(clients.to_string(), "1,5.0,2.0,7.0,false\n");
+
+    let mut clients = open_dispute()?;
+    clients.finalize_disputes(FinalizeMode::Resolve)?;
+    assert_eq!(clients.to_string(), "1,7.0,0.0,7.0,false\n");
+
+    let mut clients = open_dispute()?;
+    clients.finalize_disputes(FinalizeMode::Chargeback)?;
+    assert_eq!(clients.to_string(), "1,5.0,0.0,5.0,true\n");
+    Ok(())
+}
+
+#[test]
+fn test_process_all() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    // a reused id stops processing, reporting where
+    let mut clients = Clients::default();
+    let err = process_all(
+        &mut clients,
+        &[
+            Transaction::new(TranType::Deposit, ClientId(1), TxId(1), Some(dec!(1.0))),
+            Transaction::new(TranType::Deposit, ClientId(2), TxId(1), Some(dec!(2.0))),
+            Transaction::new(TranType::Deposit, ClientId(3), TxId(3), Some(dec!(3.0))),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(format!("{:#}", err), "Transaction 1: Reused transaction 1");
+    assert_eq!(clients.to_string(), "1,1.0,0,1.0,false\n");
+    Ok(())
+}
+
+#[test]
+fn test_paranoid_held_dp() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::new(Policy {
+        paranoid_max_dp: Some(4),
+        ..Default::default()
+    });
+    let mut ts = Vec::new();
+    for (tx, amount) in [(1, dec!(0.0001)), (2, dec!(1.2345)), (3, dec!(9.9999))] {
+        ts.push(Transaction::new(
+            TranType::Deposit,
+            ClientId(1),
+            TxId(tx),
+            Some(amount),
+        ));
+        ts.push(Transaction::new(
+            TranType::Dispute,
+            ClientId(1),
+            TxId(tx),
+            None,
+        ));
+    }
+    ts.push(Transaction::new(
+        TranType::Resolve,
+        ClientId(1),
+        TxId(2),
+        None,
+    ));
+    process_all(&mut clients, &ts)?;
+    let held = clients.balance_map[&(ClientId(1), AssetId::default())].held();
+    assert_eq!(held, dec!(10.0000));
+    assert!(held.scale() <= 4);
+
+    // anything finer than the limit trips the check
+    let mut clients = Clients::new(Policy {
+        paranoid_max_dp: Some(2),
+        ..Default::default()
+    });
+    clients.process(Transaction::new(
+        TranType::Deposit,
+        ClientId(1),
+        TxId(1),
+        Some(dec!(0.001)),
+    ))?;
+    let err = clients
+        .process(Transaction::new(
+            TranType::Dispute,
+            ClientId(1),
+            TxId(1),
+            None,
+        ))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "held 0.001 for client 1 has more than 2 dp"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_fail_on_lock() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::new(Policy {
+        fail_on_lock: true,
+        ..Default::default()
+    });
+    let err = process_all(
+        &mut clients,
+        &[
+            Transaction::new(TranType::Deposit, ClientId(1), TxId(1), Some(dec!(5.0))),
+            Transaction::new(TranType::Dispute, ClientId(1), TxId(1), None),
+            Transaction::new(TranType::Resolve, ClientId(1), TxId(1), None),
+            Transaction::new(TranType::Dispute, ClientId(1), TxId(1), None),
+            Transaction::new(TranType::Chargeback, ClientId(1), TxId(1), None),
+            Transaction::new(TranType::Deposit, ClientId(2), TxId(2), Some(dec!(1.0))),
+        ],
+    )
+    .unwrap_err();
+    let locked = err.downcast_ref::<AccountLocked>().unwrap();
+    assert_eq!((locked.client, locked.tx), (ClientId(1), TxId(1)));
+    assert_eq!(
+        format!("{:#}", err),
+        "Transaction 4: Client 1 locked by chargeback of tx 1"
+    );
+    // processing stopped there
+    assert!(!clients
+        .balance_map
+        .contains_key(&(ClientId(2), AssetId::default())));
+    Ok(())
+}
+
+#[test]
+fn test_empty_clients() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let ts = [
+        tran(TranType::Deposit, 1, 1, Some(dec!(5))),
+        // insufficient funds, client 2 ends up with an empty balance
+        tran(TranType::Withdrawal, 2, 2, Some(dec!(1))),
+        // unknown tx, client 3 never gets a balance
+        tran(TranType::Dispute, 3, 9, None),
+        // client 1 has records even if its total goes to zero
+        tran(TranType::Withdrawal, 1, 4, Some(dec!(5))),
+    ];
+
+    let mut clients = Clients::new(Policy {
+        track_appeared: true,
+        ..Default::default()
+    });
+    process_all(&mut clients, &ts)?;
+    assert_eq!(clients.empty_clients(), vec![ClientId(2), ClientId(3)]);
+
+    // combining keeps track of both sides
+    let mut other = Clients::new(clients.policy.clone());
+    process_all(&mut other, &[tran(TranType::Resolve, 4, 9, None)])?;
+    clients.combine(other, CombineMode::Strict)?;
+    assert_eq!(
+        clients.empty_clients(),
+        vec![ClientId(2), ClientId(3), ClientId(4)]
+    );
+
+    // nothing is noted unless asked for
+    let mut clients = Clients::default();
+    process_all(&mut clients, &ts)?;
+    assert!(clients.empty_clients().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_group_by_lock() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(1))),
+            tran(TranType::Deposit, 2, 2, Some(dec!(2))),
+            tran(TranType::Deposit, 3, 3, Some(dec!(3))),
+            tran(TranType::Deposit, 4, 4, Some(dec!(4))),
+            tran(TranType::Dispute, 4, 4, None),
+            tran(TranType::Chargeback, 4, 4, None),
+            tran(TranType::Dispute, 2, 2, None),
+            tran(TranType::Chargeback, 2, 2, None),
+        ],
+    )?;
+
+    let opts = OutputOptions {
+        group_by_lock: true,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    clients.write_to(&mut out, &opts)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "client,available,held,total,locked\n\
+         2,0,0,0,true\n4,0,0,0,true\n1,1,0,1,false\n3,3,0,3,false\n"
+    );
+
+    // by id alone without it
+    let mut out = Vec::new();
+    clients.write_rows(&mut out, &OutputOptions::default(), false)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "1,1,0,1,false\n2,0,0,0,true\n3,3,0,3,false\n4,0,0,0,true\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_per_type_limits() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let policy = Policy {
+        max_deposit: Some(dec!(100)),
+        max_withdrawal: Some(dec!(500)),
+        ..Default::default()
+    };
+
+    let mut clients = Clients::new(policy.clone());
+    let err = process_all(
+        &mut clients,
+        &[tran(TranType::Deposit, 1, 1, Some(dec!(100.01)))],
+    )
+    .unwrap_err();
+    assert_eq!(
+        format!("{:#}", err),
+        "Transaction 0: deposit of 100.01 for client 1 is over the deposit limit of 100"
+    );
+
+    // a withdrawal under its own limit is fine even though it's over the
+    // deposit limit, and deposits at the limit are fine
+    let mut clients = Clients::new(policy.clone());
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(100))),
+            tran(TranType::Deposit, 1, 2, Some(dec!(100))),
+            tran(TranType::Deposit, 1, 3, Some(dec!(100))),
+            tran(TranType::Withdrawal, 1, 4, Some(dec!(250))),
+        ],
+    )?;
+    assert_eq!(clients.to_string(), "1,50,0,50,false\n");
+    let err = process_all(
+        &mut clients,
+        &[tran(TranType::Withdrawal, 1, 5, Some(dec!(501)))],
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("Transaction 0"));
+
+    // a locked account ignores the row as it would any other
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Dispute, 1, 1, None),
+            tran(TranType::Chargeback, 1, 1, None),
+            tran(TranType::Deposit, 1, 6, Some(dec!(100.01))),
+        ],
+    )?;
+    assert_eq!(clients.to_string(), "1,-50,0,-50,true\n");
+
+    // ignored rather than erroring, with nothing recorded, though the client
+    // still appeared
+    let mut clients = Clients::new(Policy {
+        over_limit: OverLimit::Ignore,
+        track_appeared: true,
+        ..policy
+    });
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(100))),
+            tran(TranType::Deposit, 1, 2, Some(dec!(150))),
+            tran(TranType::Deposit, 2, 3, Some(dec!(150))),
+        ],
+    )?;
+    assert_eq!(clients.to_string(), "1,100,0,100,false\n");
+    assert_eq!(
+        clients.balance_map[&(ClientId(1), AssetId::default())].record_type(TxId(2)),
+        None
+    );
+    assert_eq!(clients.empty_clients(), vec![ClientId(2)]);
+    Ok(())
+}
+
+#[test]
+fn test_head_tail() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::default();
+    let deposits: Vec<_> = (1..=5)
+        .map(|i| {
+            Transaction::new(
+                TranType::Deposit,
+                ClientId(i),
+                TxId(i.into()),
+                Some(dec!(1)),
+            )
+        })
+        .collect();
+    process_all(&mut clients, &deposits)?;
+    let written = |opts: OutputOptions| -> Result<String, Error> {
+        let mut out = Vec::new();
+        clients.write_to(&mut out, &opts)?;
+        Ok(String::from_utf8(out)?)
+    };
+
+    let header = "client,available,held,total,locked\n";
+    let head = written(OutputOptions {
+        head: Some(2),
+        ..Default::default()
+    })?;
+    assert_eq!(head, format!("{}1,1,0,1,false\n2,1,0,1,false\n", header));
+    let tail = written(OutputOptions {
+        tail: Some(2),
+        ..Default::default()
+    })?;
+    assert_eq!(tail, format!("{}4,1,0,1,false\n5,1,0,1,false\n", header));
+
+    // asking for more than there are writes them all
+    let all = written(OutputOptions::default())?;
+    for (head, tail) in [(Some(9), None), (None, Some(9))] {
+        assert_eq!(
+            written(OutputOptions {
+                head,
+                tail,
+                ..Default::default()
+            })?,
+            all
+        );
+    }
+    assert_eq!(all.lines().count(), 6);
+    Ok(())
+}
+
+#[test]
+fn test_open_dispute_report() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::new(Policy {
+        track_disputes: true,
+        ..Default::default()
+    });
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(5))),
+            tran(TranType::Deposit, 1, 2, Some(dec!(3))),
+            tran(TranType::Dispute, 1, 1, None),
+            tran(TranType::Dispute, 1, 2, None),
+            tran(TranType::Resolve, 1, 2, None),
+            tran(TranType::Deposit, 1, 3, Some(dec!(1))),
+            tran(TranType::Deposit, 2, 4, Some(dec!(2))),
+            tran(TranType::Dispute, 2, 4, None),
+            tran(TranType::Chargeback, 2, 4, None),
+        ],
+    )?;
+    // the resolved and charged back disputes aren't open
+    assert_eq!(
+        clients.open_dispute_report(),
+        vec![OpenDispute {
+            client: ClientId(1),
+            tx: TxId(1),
+            amount: dec!(5),
+            age: 3,
+        }]
+    );
+
+    // raised again after a resolve, the age starts over
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Resolve, 1, 1, None),
+            tran(TranType::Dispute, 1, 1, None),
+        ],
+    )?;
+    assert_eq!(clients.open_dispute_report()[0].age, 0);
+    Ok(())
+}
+
+#[test]
+fn test_auto_release() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::new(Policy {
+        auto_release_after: Some(2),
+        track_disputes: true,
+        ..Default::default()
+    });
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(5))),
+            tran(TranType::Dispute, 1, 1, None),
+            tran(TranType::Deposit, 2, 2, Some(dec!(3))),
+            tran(TranType::Dispute, 2, 2, None),
+            tran(TranType::Deposit, 1, 3, Some(dec!(1))),
+        ],
+    )?;
+    // only one of each client's transactions since, so both still held,
+    // the other client's transactions don't count towards it
+    assert_eq!(clients.to_string(), "1,1,5,6,false\n2,0,3,3,false\n");
+
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Chargeback, 2, 2, None),
+            tran(TranType::Deposit, 1, 4, Some(dec!(1))),
+            tran(TranType::Deposit, 2, 5, Some(dec!(1))),
+        ],
+    )?;
+    // client 1's dispute released after two more transactions. Client 2's
+    // was charged back first, so two transactions on it stays charged back.
+    assert_eq!(clients.to_string(), "1,7,0,7,false\n2,0,0,0,true\n");
+
+    // disputed again after the release, its age starts over
+    process_all(&mut clients, &[tran(TranType::Dispute, 1, 1, None)])?;
+    assert_eq!(clients.open_dispute_report()[0].age, 0);
+    Ok(())
+}
+
+#[cfg(not(feature = "btree"))]
+#[test]
+fn test_with_capacity() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::with_capacity(Policy::default(), 1000);
+    let capacity = clients.balance_map.capacity();
+    assert!(capacity >= 1000);
+    let deposits: Vec<_> = (0..1000)
+        .map(|i| {
+            Transaction::new(
+                TranType::Deposit,
+                ClientId(i),
+                TxId(i.into()),
+                Some(dec!(1)),
+            )
+        })
+        .collect();
+    process_all(&mut clients, &deposits)?;
+    // filled without growing
+    assert_eq!(clients.balance_map.capacity(), capacity);
+    Ok(())
+}
+
+#[test]
+fn test_held_ratio_alert() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::new(Policy {
+        held_ratio_alert: Some(dec!(0.5)),
+        ..Default::default()
+    });
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(3))),
+            tran(TranType::Deposit, 1, 2, Some(dec!(7))),
+            tran(TranType::Dispute, 1, 1, None),
+        ],
+    )?;
+    // 3 of 10 held is under the limit
+    assert_eq!(
+        clients.held_ratio_alert(&(ClientId(1), AssetId::default()), dec!(0.5)),
+        None
+    );
+    process_all(&mut clients, &[tran(TranType::Dispute, 1, 2, None)])?;
+    assert_eq!(
+        clients
+            .held_ratio_alert(&(ClientId(1), AssetId::default()), dec!(0.5))
+            .as_deref(),
+        Some("client 1 has 1 of its funds held, over 0.5")
+    );
+
+    // no funds at all has no ratio rather than dividing by zero
+    let mut clients = Clients::new(clients.policy.clone());
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(3))),
+            tran(TranType::Withdrawal, 1, 2, Some(dec!(3))),
+            tran(TranType::Dispute, 1, 1, None),
+        ],
+    )?;
+    let balance = &clients.balance_map[&(ClientId(1), AssetId::default())];
+    assert_eq!((balance.held(), balance.total()), (dec!(3), Some(dec!(0))));
+    assert_eq!(balance.held_ratio(), None);
+    assert_eq!(
+        clients.held_ratio_alert(&(ClientId(1), AssetId::default()), dec!(0.5)),
+        None
+    );
+    Ok(())
+}
+
+#[test]
+fn test_write_csv() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            Transaction::new(TranType::Deposit, ClientId(2), TxId(1), Some(dec!(2.5))),
+            Transaction::new(TranType::Deposit, ClientId(1), TxId(2), Some(dec!(1))),
+        ],
+    )?;
+    let mut out = Vec::new();
+    clients.write_csv(&mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "client,available,held,total,locked\n1,1,0,1,false\n2,2.5,0,2.5,false\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_currency_totals() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let usd = Some(AssetId("USD".to_string()));
+    let eur = Some(AssetId("EUR".to_string()));
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(10))).with_asset(usd.clone()),
+            tran(TranType::Deposit, 1, 2, Some(dec!(5))).with_asset(eur.clone()),
+            tran(TranType::Deposit, 2, 3, Some(dec!(3))).with_asset(usd.clone()),
+            tran(TranType::Deposit, 3, 4, Some(dec!(1.5))).with_asset(usd.clone()),
+            tran(TranType::Dispute, 3, 4, None).with_asset(usd.clone()),
+            tran(TranType::Withdrawal, 2, 5, Some(dec!(1))).with_asset(usd.clone()),
+            tran(TranType::Deposit, 3, 6, Some(dec!(2))).with_asset(eur.clone()),
+        ],
+    )?;
+    assert_eq!(
+        clients.currency_totals()?,
+        vec![
+            CurrencyTotal {
+                asset: AssetId("EUR".to_string()),
+                available: dec!(7),
+                held: dec!(0),
+            },
+            CurrencyTotal {
+                asset: AssetId("USD".to_string()),
+                available: dec!(12.0),
+                held: dec!(1.5),
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_fill_currencies() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let usd = AssetId("USD".to_string());
+    let eur = AssetId("EUR".to_string());
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(10))).with_asset(Some(usd.clone())),
+            tran(TranType::Deposit, 1, 2, Some(dec!(5))).with_asset(Some(eur.clone())),
+            tran(TranType::Deposit, 2, 3, Some(dec!(3))).with_asset(Some(usd.clone())),
+        ],
+    )?;
+    clients.fill_currencies(&[usd, eur]);
+    // client 2 gains a zero EUR row, nothing else changes
+    assert_eq!(
+        clients.to_string(),
+        "1,EUR,5,0,5,false\n1,USD,10,0,10,false\n2,EUR,0,0,0,false\n2,USD,3,0,3,false\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_assets() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let usd = Some(AssetId("USD".to_string()));
+    let btc = Some(AssetId("BTC".to_string()));
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(10))).with_asset(usd.clone()),
+            tran(TranType::Deposit, 1, 2, Some(dec!(0.5))).with_asset(btc.clone()),
+            tran(TranType::Withdrawal, 1, 3, Some(dec!(4))).with_asset(usd.clone()),
+            // only 0.5 BTC, the USD doesn't cover it
+            tran(TranType::Withdrawal, 1, 4, Some(dec!(1))).with_asset(btc.clone()),
+        ],
+    )?;
+    assert!(clients.has_assets());
+    assert_eq!(
+        clients.to_string(),
+        "1,BTC,0.5,0,0.5,false\n1,USD,6,0,6,false\n"
+    );
+
+    // disputing the BTC deposit holds BTC only, and a dispute naming the
+    // wrong asset finds nothing
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Dispute, 1, 2, None).with_asset(btc.clone()),
+            tran(TranType::Dispute, 1, 1, None).with_asset(btc.clone()),
+        ],
+    )?;
+    assert_eq!(
+        clients.to_string(),
+        "1,BTC,0.0,0.5,0.5,false\n1,USD,6,0,6,false\n"
+    );
+    process_all(
+        &mut clients,
+        &[tran(TranType::Chargeback, 1, 2, None).with_asset(btc.clone())],
+    )?;
+    assert_eq!(
+        clients.to_string(),
+        "1,BTC,0.0,0.0,0.0,true\n1,USD,6,0,6,false\n"
+    );
+
+    // shards merge per client and asset
+    let mut other = Clients::default();
+    process_all(
+        &mut other,
+        &[tran(TranType::Deposit, 1, 5, Some(dec!(1))).with_asset(usd.clone())],
+    )?;
+    assert!(clients.combine(other, CombineMode::Strict).is_err());
+    let mut other = Clients::default();
+    process_all(
+        &mut other,
+        &[
+            tran(TranType::Deposit, 1, 6, Some(dec!(1))).with_asset(usd.clone()),
+            tran(TranType::Deposit, 1, 7, Some(dec!(2))).with_asset(None.clone()),
+        ],
+    )?;
+    clients.combine(other, CombineMode::Merge)?;
+    let mut out = Vec::new();
+    clients.write_csv(&mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "client,asset,available,held,total,locked\n1,,2,0,2,false\n1,BTC,0.0,0.0,0.0,true\n1,USD,7,0,7,false\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_write_records() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            Transaction::new(TranType::Deposit, ClientId(2), TxId(3), Some(dec!(1.5))),
+            Transaction::new(TranType::Deposit, ClientId(1), TxId(2), Some(dec!(5))),
+            Transaction::new(TranType::Deposit, ClientId(1), TxId(1), Some(dec!(2))),
+            Transaction::new(TranType::Withdrawal, ClientId(1), TxId(4), Some(dec!(3))),
+            // ignored for insufficient funds, so not recorded
+            Transaction::new(TranType::Withdrawal, ClientId(2), TxId(5), Some(dec!(9))),
+            Transaction::new(TranType::Dispute, ClientId(1), TxId(2), None),
+            Transaction::new(TranType::Dispute, ClientId(2), TxId(3), None),
+            Transaction::new(TranType::Resolve, ClientId(2), TxId(3), None),
+        ],
+    )?;
+    let mut out = Vec::new();
+    clients.write_records(&mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "client,tx,type,amount,disputed
+1,1,deposit,2,false
+1,2,deposit,5,true
+1,4,withdrawal,3,false
+2,3,deposit,1.5,false
+"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_sort_by() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(5))),
+            tran(TranType::Deposit, 2, 2, Some(dec!(1))),
+            tran(TranType::Deposit, 2, 3, Some(dec!(6))),
+            tran(TranType::Dispute, 2, 3, None),
+            tran(TranType::Deposit, 3, 4, Some(dec!(2))),
+            tran(TranType::Deposit, 4, 5, Some(dec!(5))),
+        ],
+    )?;
+    let order = |sort_by, desc| -> Vec<u16> {
+        let opts = OutputOptions {
+            sort_by,
+            desc,
+            ..Default::default()
+        };
+        clients
+            .iter_output(&opts)
+            .map(|((client, _), _)| client.id())
+            .collect()
+    };
+    assert_eq!(order(SortBy::Id, false), vec![1, 2, 3, 4]);
+    assert_eq!(order(SortBy::Id, true), vec![4, 3, 2, 1]);
+    // totals 5, 7, 2, 5, the tie between 1 and 4 in id order either way
+    assert_eq!(order(SortBy::Total, false), vec![3, 1, 4, 2]);
+    assert_eq!(order(SortBy::Total, true), vec![2, 1, 4, 3]);
+    // available 5, 1, 2, 5
+    assert_eq!(order(SortBy::Available, true), vec![1, 4, 3, 2]);
+    // held 0, 6, 0, 0
+    assert_eq!(order(SortBy::Held, false), vec![1, 3, 4, 2]);
+    assert_eq!(order(SortBy::Held, true), vec![2, 1, 3, 4]);
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use anyhow::{bail, Context, Error};
+use csv::StringRecord;
+
+use crate::transaction::ParseOptions;
+
+/// Checks an optional trailing control row, e.g. `control,0,0,42`, whose
+/// amount column is the number of transaction rows before it. The control
+/// row itself is not a transaction.
+#[derive(Debug)]
+pub struct ControlCheck<'a> {
+    name: Option<&'a str>,
+    type_col: Option<usize>,
+    count_col: Option<usize>,
+    processed: u64,
+    seen: bool,
+}
+
+impl<'a> ControlCheck<'a> {
+    pub fn new(opts: &'a ParseOptions, headers: &StringRecord) -> Self {
+        Self {
+            name: opts.control_type.as_deref(),
+            type_col: headers.iter().position(|h| h == "type"),
+            count_col: headers.iter().position(|h| h == "amount"),
+            processed: 0,
+            seen: false,
+        }
+    }
+
+    /// Returns true if this is the control row, which must match the count
+    /// of rows so far, false for a transaction row
+    pub fn check(&mut self, record: &StringRecord) -> Result<bool, Error> {
+        let name = match self.name {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+        if self.seen {
+            bail!("Row after the {} row, it must be last", name);
+        }
+        if self.type_col.and_then(|i| record.get(i)) != Some(name) {
+            self.processed += 1;
+            return Ok(false);
+        }
+        self.seen = true;
+        let count = self
+            .count_col
+            .and_then(|i| record.get(i))
+            .unwrap_or_default();
+        let expected: u64 = count
+            .parse()
+            .with_context(|| format!("Invalid {} count {:?}", name, count))?;
+        if expected != self.processed {
+            bail!(
+                "{} row expects {} transactions, read {}",
+                name,
+                expected,
+                self.processed
+            );
+        }
+        Ok(true)
+    }
+
+    /// Once all rows are read, error if a control row was expected but missing
+    pub fn finish(&self) -> Result<(), Error> {
+        match self.name {
+            Some(name) if !self.seen => bail!("Missing trailing {} row", name),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_control_check() -> Result<(), Error> {
+    let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    let row = |s: &str| StringRecord::from_iter(s.split(','));
+    let opts = ParseOptions {
+        control_type: Some("control".to_string()),
+        ..Default::default()
+    };
+
+    // matching count
+    let mut control = ControlCheck::new(&opts, &headers);
+    assert!(!control.check(&row("deposit,1,1,1.0"))?);
+    assert!(!control.check(&row("dispute,1,1,"))?);
+    assert!(control.finish().is_err());
+    assert!(control.check(&row("control,0,0,2"))?);
+    control.finish()?;
+    assert!(control.check(&row("deposit,1,2,1.0")).is_err());
+
+    // mismatched count
+    let mut control = ControlCheck::new(&opts, &headers);
+    assert!(!control.check(&row("deposit,1,1,1.0"))?);
+    let err = control.check(&row("control,0,0,3")).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "control row expects 3 transactions, read 1"
+    );
+
+    // not configured, everything is a transaction
+    let opts = ParseOptions::default();
+    let mut control = ControlCheck::new(&opts, &headers);
+    assert!(!control.check(&row("control,0,0,0"))?);
+    control.finish()?;
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use anyhow::{anyhow, bail, Error};
+use clap::ValueEnum;
+
+use std::collections::HashSet;
+
+use crate::ids::{ClientId, TxId};
+use crate::transaction::{TranType, Transaction};
+
+/// Where a deposit or withdrawal tx id has to be unique
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum TxScope {
+    /// Across the whole input
+    #[default]
+    Global,
+    /// Within each client, for systems that only number a client's own transactions
+    PerClient,
+}
+
+/// Tracks deposit and withdrawal transaction ids seen so far.
+///
+/// This owns duplicate detection. It runs centrally before transactions are
+/// sharded, so it covers reuse within one client and across clients alike,
+/// or with `TxScope::PerClient` within one client only. Every deposit or
+/// withdrawal row consumes its id, even if the shard later ignores it (e.g.
+/// insufficient funds or locked account). `Balance` only guards its own
+/// records against being overwritten by a caller that skips this.
+#[derive(Debug, Default)]
+pub struct SeenTx {
+    scope: TxScope,
+    seen: HashSet<TxId>,
+    /// Used instead of `seen` for `TxScope::PerClient`
+    seen_per_client: HashSet<(ClientId, TxId)>,
+}
+
+impl SeenTx {
+    pub fn new(scope: TxScope) -> Self {
+        Self::with_capacity(scope, 0)
+    }
+
+    /// Pre-size for the expected number of deposits and withdrawals
+    pub fn with_capacity(scope: TxScope, transactions: usize) -> Self {
+        let (global, per_client) = match scope {
+            TxScope::Global => (transactions, 0),
+            TxScope::PerClient => (0, transactions),
+        };
+        Self {
+            scope,
+            seen: HashSet::with_capacity(global),
+            seen_per_client: HashSet::with_capacity(per_client),
+        }
+    }
+
+    /// Record the transaction id, erroring if it was already used
+    pub fn check(&mut self, t: &Transaction) -> Result<(), Error> {
+        if uses_id(t.tran_type) && !self.insert(t) {
+            return Err(self.reused(t));
+        }
+        Ok(())
+    }
+
+    /// Error if the transaction id was already used, without recording it.
+    /// Pair with `record` once the transaction has been applied.
+    pub fn verify(&self, t: &Transaction) -> Result<(), Error> {
+        let used = match self.scope {
+            TxScope::Global => self.seen.contains(&t.tx),
+            TxScope::PerClient => self.seen_per_client.contains(&(t.client, t.tx)),
+        };
+        if uses_id(t.tran_type) && used {
+            return Err(self.reused(t));
+        }
+        Ok(())
+    }
+
+    /// Record the transaction id as used
+    pub fn record(&mut self, t: &Transaction) {
+        if uses_id(t.tran_type) {
+            self.insert(t);
+        }
+    }
+
+    fn insert(&mut self, t: &Transaction) -> bool {
+        match self.scope {
+            TxScope::Global => self.seen.insert(t.tx),
+            TxScope::PerClient => self.seen_per_client.insert((t.client, t.tx)),
+        }
+    }
+
+    fn reused(&self, t: &Transaction) -> Error {
+        match self.scope {
+            TxScope::Global => anyhow!("Reused transaction {}", t.tx.id()),
+            TxScope::PerClient => anyhow!(
+                "Reused transaction {} for client {}",
+                t.tx.id(),
+                t.client.id()
+            ),
+        }
+    }
+}
+
+/// Whether the transaction creates a record under its own id
+fn uses_id(tran_type: TranType) -> bool {
+    match tran_type {
+        TranType::Deposit | TranType::Withdrawal => true,
+        // These refer to an earlier transaction rather than creating one
+        TranType::Dispute | TranType::Resolve | TranType::Chargeback => false,
+        // Admin adjustments create no record, so their id is never referred to
+        TranType::Mint | TranType::Burn => false,
+    }
+}
+
+/// Checks deposit and withdrawal tx ids only ever increase, for feeds meant
+/// to be sorted by tx id. Disputes, resolves and chargebacks refer back to
+/// earlier ids so are exempt.
+#[derive(Debug, Default)]
+pub struct TxOrder {
+    last: Option<TxId>,
+}
+
+impl TxOrder {
+    /// Record the transaction id, erroring if it isn't after the last one
+    pub fn check(&mut self, t: &Transaction) -> Result<(), Error> {
+        self.verify(t)?;
+        self.record(t);
+        Ok(())
+    }
+
+    /// Error if the transaction id isn't after the last one, without
+    /// recording it
+    pub fn verify(&self, t: &Transaction) -> Result<(), Error> {
+        match self.last {
+            Some(last) if uses_id(t.tran_type) && t.tx.id() <= last.id() => bail!(
+                "Transaction {} is not after {}, input is not sorted by tx id",
+                t.tx.id(),
+                last.id()
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record the transaction id as the latest
+    pub fn record(&mut self, t: &Transaction) {
+        if uses_id(t.tran_type) {
+            self.last = Some(t.tx);
+        }
+    }
+}
+
+#[test]
+fn test_check() -> Result<(), Error> {
+    use crate::ids::ClientId;
+    use rust_decimal_macros::dec;
+
+    let mut seen = SeenTx::default();
+    seen.check(&Transaction::new(
+        TranType::Deposit,
+        ClientId(1),
+        TxId(1),
+        Some(dec!(1.0)),
+    ))?;
+
+    // same client reuse
+    assert!(seen
+        .check(&Transaction::new(
+            TranType::Withdrawal,
+            ClientId(1),
+            TxId(1),
+            Some(dec!(1.0)),
+        ))
+        .is_err());
+
+    // cross client reuse
+    assert!(seen
+        .check(&Transaction::new(
+            TranType::Deposit,
+            ClientId(2),
+            TxId(1),
+            Some(dec!(1.0)),
+        ))
+        .is_err());
+
+    // dispute flow refers back to the id rather than reusing it
+    seen.check(&Transaction::new(
+        TranType::Dispute,
+        ClientId(1),
+        TxId(1),
+        None,
+    ))?;
+    seen.check(&Transaction::new(
+        TranType::Resolve,
+        ClientId(1),
+        TxId(1),
+        None,
+    ))?;
+    seen.check(&Transaction::new(
+        TranType::Chargeback,
+        ClientId(1),
+        TxId(1),
+        None,
+    ))?;
+
+    // a new id is fine
+    seen.check(&Transaction::new(
+        TranType::Withdrawal,
+        ClientId(2),
+        TxId(2),
+        Some(dec!(1.0)),
+    ))?;
+    Ok(())
+}
+
+#[test]
+fn test_tx_scope() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let deposit =
+        |client, tx| Transaction::new(TranType::Deposit, ClientId(client), TxId(tx), Some(dec!(1)));
+    let mut global = SeenTx::new(TxScope::Global);
+    global.check(&deposit(1, 7))?;
+    let err = global.check(&deposit(2, 7)).unwrap_err();
+    assert_eq!(err.to_string(), "Reused transaction 7");
+
+    // the same id under another client is its own transaction
+    let mut per_client = SeenTx::new(TxScope::PerClient);
+    per_client.check(&deposit(1, 7))?;
+    per_client.check(&deposit(2, 7))?;
+    let err = per_client.check(&deposit(1, 7)).unwrap_err();
+    assert_eq!(err.to_string(), "Reused transaction 7 for client 1");
+    Ok(())
+}
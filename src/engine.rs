@@ -0,0 +1,252 @@
+use anyhow::Error;
+use rust_decimal::Decimal;
+
+use crate::balance::Policy;
+use crate::clients::{Applied, Clients};
+use crate::dedup::{SeenTx, TxOrder, TxScope};
+use crate::ids::{AssetId, ClientId};
+use crate::transaction::Transaction;
+
+/// Settings for an `Engine`, the parts of `ProcessOptions` that apply to
+/// already parsed transactions
+#[derive(Clone, Debug, Default)]
+pub struct EngineConfig {
+    pub policy: Policy,
+    /// Where deposit and withdrawal tx ids have to be unique
+    pub tx_scope: TxScope,
+    /// Error if deposit and withdrawal tx ids don't strictly increase
+    pub assert_sorted: bool,
+}
+
+/// One account's balance as of a snapshot
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BalanceView {
+    pub client: ClientId,
+    /// Empty for the implicit asset of input without an asset column
+    pub asset: AssetId,
+    pub available: Decimal,
+    pub held: Decimal,
+    /// None if available plus held overflows
+    pub total: Option<Decimal>,
+    pub locked: bool,
+}
+
+/// Counts of what an `Engine` has done so far
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Transactions applied, including ones ignored under the rules, e.g. a
+    /// withdrawal with insufficient funds, and ones that took effect before
+    /// erroring, e.g. a chargeback under `Policy::fail_on_lock`
+    pub applied: u64,
+    /// Transactions refused with an error, leaving everything as it was
+    pub rejected: u64,
+    pub accounts: usize,
+    pub locked: usize,
+    pub open_disputes: usize,
+}
+
+/// Applies transactions one at a time in the calling thread, for embedding
+/// without CSV input or an async runtime.
+///
+/// Transactions are checked for tx id reuse, and order if asked, as the CSV
+/// pipeline does. Each of the pipeline's shards is an `Engine` fed after
+/// those checks, the CSV pipeline spreading clients over cores.
+#[derive(Debug)]
+pub struct Engine {
+    clients: Clients,
+    /// None when the ids were checked before the transactions got here
+    seen_tx: Option<SeenTx>,
+    order: Option<TxOrder>,
+    stats: Stats,
+}
+
+impl Engine {
+    pub fn new(config: EngineConfig) -> Self {
+        Self {
+            clients: Clients::new(config.policy),
+            seen_tx: Some(SeenTx::new(config.tx_scope)),
+            order: config.assert_sorted.then(TxOrder::default),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Apply transactions to existing clients, e.g. seeded with opening
+    /// balances, whose tx ids are checked centrally before they are sharded
+    pub fn for_shard(clients: Clients) -> Self {
+        Self {
+            clients,
+            seen_tx: None,
+            order: None,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Apply a transaction. If it is rejected, e.g. for a reused tx id or
+    /// over a limit, nothing changes, its id included, so the caller can
+    /// report it and carry on. Errors after it took effect, e.g.
+    /// `AccountLocked`, count as applied.
+    pub fn apply(&mut self, t: Transaction) -> Result<(), Error> {
+        let applied = match self.check_and_apply(t) {
+            Ok(applied) => applied,
+            Err(e) => {
+                self.stats.rejected += 1;
+                return Err(e);
+            }
+        };
+        self.stats.applied += 1;
+        self.clients.after_apply(applied)
+    }
+
+    /// Apply the transaction, recording its id only once it has been applied
+    fn check_and_apply(&mut self, t: Transaction) -> Result<Applied, Error> {
+        if let Some(seen_tx) = &self.seen_tx {
+            seen_tx.verify(&t)?;
+        }
+        if let Some(order) = &self.order {
+            order.verify(&t)?;
+        }
+        let id = Transaction::new(t.tran_type, t.client, t.tx, None);
+        let applied = self.clients.apply(t)?;
+        if let Some(seen_tx) = &mut self.seen_tx {
+            seen_tx.record(&id);
+        }
+        if let Some(order) = &mut self.order {
+            order.record(&id);
+        }
+        Ok(applied)
+    }
+
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    /// Every account's balance, by client then asset
+    pub fn snapshot(&self) -> Vec<BalanceView> {
+        self.clients
+            .iter_sorted()
+            .map(|((client, asset), balance)| BalanceView {
+                client: *client,
+                asset: asset.clone(),
+                available: balance.available(),
+                held: balance.held(),
+                total: balance.total(),
+                locked: balance.locked(),
+            })
+            .collect()
+    }
+
+    /// The counts so far, with the accounts, locks and open disputes as of now
+    pub fn drain_stats(&self) -> Stats {
+        Stats {
+            accounts: self.clients.balance_map.len(),
+            locked: self
+                .clients
+                .balance_map
+                .values()
+                .filter(|balance| balance.locked())
+                .count(),
+            open_disputes: self.clients.open_dispute_report().len(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// The clients, e.g. to finalize disputes or write them out
+    pub fn into_clients(self) -> Clients {
+        self.clients
+    }
+}
+
+#[test]
+fn test_engine() -> Result<(), Error> {
+    use crate::clients::tran;
+    use crate::transaction::TranType;
+    use rust_decimal_macros::dec;
+
+    let mut engine = Engine::new(EngineConfig::default());
+    for t in [
+        tran(TranType::Deposit, 2, 1, Some(dec!(5))),
+        tran(TranType::Deposit, 1, 2, Some(dec!(3))),
+        tran(TranType::Dispute, 1, 2, None),
+        tran(TranType::Withdrawal, 2, 3, Some(dec!(9))),
+        tran(TranType::Deposit, 2, 4, Some(dec!(1))),
+        tran(TranType::Dispute, 2, 4, None),
+        tran(TranType::Chargeback, 2, 4, None),
+    ] {
+        engine.apply(t)?;
+    }
+    // a reused tx id is refused, the rest carries on
+    assert!(engine
+        .apply(tran(TranType::Deposit, 1, 1, Some(dec!(7))))
+        .is_err());
+
+    assert_eq!(
+        engine.snapshot(),
+        vec![
+            BalanceView {
+                client: ClientId(1),
+                asset: AssetId::default(),
+                available: dec!(0),
+                held: dec!(3),
+                total: Some(dec!(3)),
+                locked: false,
+            },
+            BalanceView {
+                client: ClientId(2),
+                asset: AssetId::default(),
+                available: dec!(5),
+                held: dec!(0),
+                total: Some(dec!(5)),
+                locked: true,
+            },
+        ]
+    );
+    assert_eq!(
+        engine.drain_stats(),
+        Stats {
+            applied: 7,
+            rejected: 1,
+            accounts: 2,
+            locked: 1,
+            open_disputes: 1,
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_engine_rejected() -> Result<(), Error> {
+    use crate::balance::OverLimit;
+    use crate::clients::{tran, AccountLocked};
+    use crate::transaction::TranType;
+    use rust_decimal_macros::dec;
+
+    let mut engine = Engine::new(EngineConfig {
+        policy: Policy {
+            max_deposit: Some(dec!(10)),
+            over_limit: OverLimit::Error,
+            fail_on_lock: true,
+            ..Default::default()
+        },
+        assert_sorted: true,
+        ..Default::default()
+    });
+    // rejected over the limit, so the corrected row can reuse its id
+    assert!(engine
+        .apply(tran(TranType::Deposit, 1, 1, Some(dec!(11))))
+        .is_err());
+    engine.apply(tran(TranType::Deposit, 1, 1, Some(dec!(5))))?;
+    assert!(engine
+        .apply(tran(TranType::Deposit, 1, 1, Some(dec!(5))))
+        .is_err());
+
+    // the chargeback locked the account before its error
+    engine.apply(tran(TranType::Dispute, 1, 1, None))?;
+    let err = engine
+        .apply(tran(TranType::Chargeback, 1, 1, None))
+        .unwrap_err();
+    assert!(err.is::<AccountLocked>());
+    assert!(engine.snapshot()[0].locked);
+    let stats = engine.drain_stats();
+    assert_eq!((stats.applied, stats.rejected), (3, 2));
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use anyhow::{bail, Context, Error};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+use crate::ids::ClientId;
+
+/// One row of a hints file
+#[derive(Debug, Deserialize)]
+struct HintRow {
+    client: ClientId,
+    shard: u16,
+}
+
+/// Shard overrides for known hot clients, everyone else routes by modulo.
+///
+/// A hinted shard beyond the shard count wraps by modulo too, so each client
+/// still maps to exactly one shard whatever the machine.
+#[derive(Clone, Debug, Default)]
+pub struct ShardHints {
+    shards: HashMap<ClientId, u16>,
+}
+
+impl ShardHints {
+    /// Read `client,shard` rows, a client listed twice is an error
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("Opening shard hints {}", path))?;
+        let mut shards = HashMap::new();
+        for result in rdr.deserialize() {
+            let row: HintRow = result.with_context(|| format!("Reading shard hints {}", path))?;
+            if shards.insert(row.client, row.shard).is_some() {
+                bail!("Client {} hinted more than once", row.client.id());
+            }
+        }
+        Ok(Self { shards })
+    }
+
+    /// The shard a client's transactions go to
+    pub fn shard_for(&self, client: ClientId, num_shards: u16) -> u16 {
+        match self.shards.get(&client) {
+            Some(shard) => shard % num_shards,
+            None => client.id() % num_shards,
+        }
+    }
+}
+
+#[test]
+fn test_shard_hints() -> Result<(), Error> {
+    use crate::testutil::write_test_csv;
+
+    let path = write_test_csv("hints.csv", "client,shard\n1,2\n5, 3\n9,6\n")?;
+    let hints = ShardHints::from_path(&path);
+    std::fs::remove_file(&path)?;
+    let hints = hints?;
+
+    assert_eq!(hints.shard_for(ClientId(1), 4), 2);
+    assert_eq!(hints.shard_for(ClientId(5), 4), 3);
+    // out of range hints wrap
+    assert_eq!(hints.shard_for(ClientId(9), 4), 2);
+    // unhinted clients use modulo
+    assert_eq!(hints.shard_for(ClientId(7), 4), 3);
+    assert_eq!(ShardHints::default().shard_for(ClientId(5), 4), 1);
+
+    std::fs::write(&path, "client,shard\n1,2\n1,3\n")?;
+    let err = ShardHints::from_path(&path).unwrap_err();
+    std::fs::remove_file(&path)?;
+    assert_eq!(err.to_string(), "Client 1 hinted more than once");
+    Ok(())
+}
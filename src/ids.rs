@@ -19,3 +19,17 @@ impl TxId {
         self.0
     }
 }
+
+/// The input asset (currency) id, e.g. USD or BTC. The default, empty, id is
+/// the single implicit asset of input with no asset column.
+#[derive(Clone, Debug, Default, Deserialize, Hash, Eq, Ord, PartialOrd, PartialEq)]
+pub struct AssetId(pub String);
+
+impl AssetId {
+    pub fn is_implicit(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// One client's holding of one asset, the key balances are kept under
+pub type AccountId = (ClientId, AssetId);
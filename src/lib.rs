@@ -0,0 +1,44 @@
+//! Simple example payments engine.
+//!
+//! The binary reads a CSV of transactions and writes the resulting client
+//! balances. The same pipeline can be embedded, e.g. reading from a network
+//! stream rather than a file:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), anyhow::Error> {
+//! let input = std::io::stdin();
+//! let clients = paytoy::process_reader(input, paytoy::ProcessOptions::default()).await?;
+//! clients.write_csv(std::io::stdout())?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Or transactions built in code can be applied one at a time with an
+//! [`Engine`], without CSV or an async runtime.
+
+pub mod allow;
+pub mod analyze;
+pub mod balance;
+pub mod channel;
+pub mod clients;
+pub mod control;
+pub mod dedup;
+pub mod engine;
+pub mod hints;
+pub mod ids;
+pub mod locked;
+pub mod opening;
+pub mod output;
+pub mod partition;
+pub mod pipeline;
+pub mod rates;
+pub mod release;
+pub mod reorder;
+pub mod schema;
+pub mod transaction;
+
+#[cfg(test)]
+mod testutil;
+
+pub use engine::{Engine, EngineConfig};
+pub use pipeline::{process_reader, ProcessOptions};
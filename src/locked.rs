@@ -0,0 +1,83 @@
+use tokio::sync::mpsc;
+
+use std::collections::HashSet;
+
+use crate::ids::AccountId;
+use crate::transaction::{TranType, Transaction};
+
+/// Shard side of the back channel, reports client assets as they are locked
+#[derive(Clone, Debug)]
+pub struct LockNotifier(mpsc::UnboundedSender<AccountId>);
+
+impl LockNotifier {
+    pub fn locked(&self, account: AccountId) {
+        // the reader may already be done, then nobody needs telling
+        let _ = self.0.send(account);
+    }
+}
+
+/// Reader side of the back channel, the client assets known to be locked so
+/// far. A client's other assets carry on as normal.
+///
+/// Notifications arrive some time after the chargeback was sent, so a few
+/// transactions for a newly locked client can still reach its shard. That is
+/// fine as the shard ignores them anyway, this only saves queue space and CPU.
+#[derive(Debug)]
+pub struct LockedClients {
+    rx: mpsc::UnboundedReceiver<AccountId>,
+    locked: HashSet<AccountId>,
+}
+
+pub fn lock_channel() -> (LockNotifier, LockedClients) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (
+        LockNotifier(tx),
+        LockedClients {
+            rx,
+            locked: HashSet::new(),
+        },
+    )
+}
+
+impl LockedClients {
+    /// Whether the transaction can be dropped rather than sent to its shard
+    pub fn skip(&mut self, t: &Transaction) -> bool {
+        while let Ok(account) = self.rx.try_recv() {
+            self.locked.insert(account);
+        }
+        match t.tran_type {
+            // admin adjustments still apply to locked accounts
+            TranType::Mint | TranType::Burn => false,
+            _ => !self.locked.is_empty() && self.locked.contains(&t.account()),
+        }
+    }
+}
+
+#[test]
+fn test_skip_locked() {
+    use crate::ids::{AssetId, ClientId, TxId};
+    use rust_decimal_macros::dec;
+
+    let (notifier, mut locked) = lock_channel();
+    let deposit = Transaction::new(TranType::Deposit, ClientId(1), TxId(1), Some(dec!(1)));
+    let other = Transaction::new(TranType::Deposit, ClientId(2), TxId(2), Some(dec!(1)));
+    let mint = Transaction::new(TranType::Mint, ClientId(1), TxId(3), Some(dec!(1)));
+    assert!(!locked.skip(&deposit));
+
+    notifier.locked((ClientId(1), AssetId::default()));
+    assert!(locked.skip(&deposit));
+    assert!(locked.skip(&Transaction::new(
+        TranType::Dispute,
+        ClientId(1),
+        TxId(1),
+        None
+    )));
+    assert!(!locked.skip(&other));
+    assert!(!locked.skip(&mint));
+    // only the locked asset
+    assert!(!locked.skip(&deposit.with_asset(Some(AssetId("BTC".to_string())))));
+
+    // a finished reader doesn't trouble the shards
+    drop(locked);
+    notifier.locked((ClientId(2), AssetId::default()));
+}
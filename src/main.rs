@@ -1,101 +1,590 @@
-use anyhow::{bail, Error};
+use anyhow::{bail, Context, Error};
 use clap::Parser;
-use csv::{ReaderBuilder, Trim};
+use rust_decimal::Decimal;
 
-use futures::future::try_join_all;
-use tokio::sync::mpsc;
+use std::fs::File;
+use std::io::{stdin, stdout, BufWriter, Write};
+use std::path::Path;
 
-use std::cmp::min;
-use std::collections::HashSet;
-
-mod balance;
-mod clients;
-mod ids;
-mod transaction;
-
-use crate::clients::Clients;
-use crate::transaction::TranType;
-
-const SHARD_QUEUE_MAX: usize = 1_000_000;
+use paytoy::allow::AllowClients;
+use paytoy::balance::{OverLimit, Policy};
+use paytoy::channel::ChannelKind;
+use paytoy::clients::{AccountLocked, Clients, CombineMode, FinalizeMode};
+use paytoy::dedup::TxScope;
+use paytoy::hints::ShardHints;
+use paytoy::ids::AssetId;
+use paytoy::opening::OpeningBalances;
+use paytoy::output::{HashingWriter, OutputOptions, SortBy};
+use paytoy::pipeline::{
+    analyze_csv, open_input, process_csv, process_csv_low_memory, run_jobs, ProcessOptions,
+};
+use paytoy::rates::Rates;
+use paytoy::transaction::{ParseOptions, TranType};
+use paytoy::{output, partition, schema, transaction};
 
 #[derive(Parser)]
 #[clap(name = "paytoy", about = "Simple example payments engine")]
 struct Args {
     /// Input CSV file with header row: type, client, tx, amount
-    #[clap(required = true)]
-    input: String,
+    #[clap(required_unless_present_any = ["print-schema", "jobs"])]
+    input: Option<String>,
+
+    /// Accept comma thousands separators in quoted amounts, e.g. "1,234.56"
+    #[clap(long)]
+    allow_grouping: bool,
+
+    /// Add a lock_reason column to the output, from the chargeback reason column
+    #[clap(long)]
+    lock_reasons: bool,
+
+    /// Report the per shard transaction count spread to stderr, without processing balances
+    #[clap(long)]
+    analyze: bool,
+
+    /// Print a SHA-256 of the raw input bytes, `-` being stdin, without processing it
+    #[clap(long, conflicts_with = "jobs")]
+    input_hash: bool,
+
+    /// How to combine shard results if the same client appears in more than one
+    #[clap(long, value_enum, default_value = "strict")]
+    combine: CombineMode,
+
+    /// Validate the whole file and report every problem before processing any of it
+    #[clap(long)]
+    two_phase: bool,
+
+    /// Maximum decimal places allowed in amounts
+    #[clap(long, default_value_t = transaction::MAX_DP)]
+    max_dp: u32,
+
+    /// Decimal places for one asset, overriding --max-dp, e.g. --dp JPY=0 --dp BTC=8
+    #[clap(long = "dp", value_parser = parse_asset_dp)]
+    asset_dp: Vec<(AssetId, u32)>,
+
+    /// Ignore trailing zeros in amounts when checking decimal places, e.g. accept 1.23000 as 1.23
+    #[clap(long)]
+    trim_trailing_zeros: bool,
+
+    /// Only process rows with this value in the batch column, which must be present
+    #[clap(long)]
+    batch: Option<String>,
+
+    /// Remove whitespace inside the type before matching it, for feeds that pad values, e.g. "dep osit"
+    #[clap(long)]
+    normalize_type_whitespace: bool,
+
+    /// Where deposit and withdrawal tx ids have to be unique, across the input or within each client
+    #[clap(long, value_enum, default_value = "global")]
+    tx_scope: TxScope,
+
+    /// Error if a deposit or withdrawal tx id isn't greater than the one before, for feeds sorted by tx id
+    #[clap(long)]
+    assert_sorted: bool,
+
+    /// Error on any row that isn't this type, for files meant to hold only one, e.g. deposit
+    #[clap(long, value_parser = parse_tran_type)]
+    expect_type: Option<TranType>,
+
+    /// Pre-size the client maps for about this many clients, saving rehashing as they arrive
+    #[clap(long)]
+    expected_clients: Option<usize>,
+
+    /// Pre-size the tx id set for about this many deposits and withdrawals
+    #[clap(long)]
+    expected_transactions: Option<usize>,
+
+    /// Also write each shard's clients to <prefix>-shard-<n>.csv, to see how clients were routed
+    #[clap(long, conflicts_with_all = &["low-memory", "jobs"])]
+    per_shard_output: Option<String>,
+
+    /// Apply transactions in timestamp column order, reordering within a window of this many per shard
+    #[clap(long)]
+    reorder_window: Option<usize>,
+
+    /// Refuse to start if the input file is larger than this many bytes
+    #[clap(long)]
+    max_input_bytes: Option<u64>,
+
+    /// Print the expected input format and rules, then exit
+    #[clap(long)]
+    print_schema: bool,
+
+    /// Process independent files concurrently, writing each result to its own output, e.g. --job a.csv=a.out
+    #[clap(long = "job", value_parser = parse_job, conflicts_with = "input")]
+    jobs: Vec<(String, String)>,
+
+    /// Have each shard write its rows to a temporary file and merge them, rather than combining in memory
+    #[clap(long)]
+    low_memory: bool,
+
+    /// Only deposits may be disputed, error if a dispute, resolve or chargeback refers to a withdrawal
+    #[clap(long)]
+    strict_tx_semantics: bool,
+
+    /// Shard queue kind, unbounded never blocks the reader but can use more memory
+    #[clap(long, value_enum, default_value = "bounded")]
+    channel: ChannelKind,
+
+    /// Print available or held amounts within this of zero as 0
+    #[clap(long)]
+    zero_threshold: Option<Decimal>,
+
+    /// Print amounts as a whole number of this unit, e.g. 0.01 for cents, erroring if one isn't exact
+    #[clap(long, value_parser = parse_scale)]
+    output_scale: Option<Decimal>,
+
+    /// What to do with disputes still open at the end of the input
+    #[clap(long, value_enum, default_value = "leave")]
+    finalize_disputes: FinalizeMode,
+
+    /// Split the output into this many files by client id range, named <prefix>-<n>.csv
+    #[clap(
+        long,
+        requires = "output-prefix",
+        conflicts_with_all = &["low-memory", "checksum", "jobs"],
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    output_parts: Option<u16>,
+
+    /// File name prefix for --output-parts
+    #[clap(long, requires = "output-parts")]
+    output_prefix: Option<String>,
+
+    /// Print locked accounts' amounts as 0, so reports don't expose frozen funds
+    #[clap(long)]
+    mask_locked: bool,
+
+    /// Error if no clients would be output, e.g. the wrong, header only, file was given
+    #[clap(long, conflicts_with = "low-memory")]
+    require_output: bool,
+
+    /// CSV of client,shard assignments putting hot clients on their own shards, others use modulo
+    #[clap(long)]
+    shard_hints: Option<String>,
+
+    /// Check after every transaction that held has no more decimal places than the inputs allow
+    #[clap(long)]
+    paranoid: bool,
+
+    /// Skip leading lines starting with this before the header, e.g. feed metadata, empty to disable
+    #[clap(long, default_value = "#")]
+    comment_prefix: String,
+
+    /// If a shard fails, e.g. on overflow, warn and still output the other shards' clients
+    #[clap(long)]
+    keep_good_shards: bool,
+
+    /// Add total_deposited and total_withdrawn columns to the output
+    #[clap(long)]
+    with_flows: bool,
+
+    /// Ignore withdrawals that would leave available below this floor
+    #[clap(long, default_value = "0")]
+    keep_minimum: Decimal,
+
+    /// Type of a trailing control row whose amount is the count of rows before it, checked rather than processed
+    #[clap(long)]
+    control_type: Option<String>,
+
+    /// Drop transactions for locked clients before they are queued to their shard
+    #[clap(long, conflicts_with = "strict-tx-semantics")]
+    skip_locked: bool,
+
+    /// Stop as soon as any chargeback locks an account, exiting with code 3
+    #[clap(long)]
+    fail_on_any_lock: bool,
+
+    /// Accept mint and burn admin transactions, which adjust available directly, for test fixtures
+    #[clap(long)]
+    allow_admin: bool,
+
+    /// Print a SHA-256 of the output to stderr, so it can be checked downstream
+    #[clap(long)]
+    checksum: bool,
+
+    /// Ignore disputes of a transaction already disputed this many times
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    max_dispute_cycles: Option<u16>,
+
+    /// Resolve a dispute once this many more of the client's transactions arrive without a chargeback
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    auto_release_after: Option<u32>,
+
+    /// Only process these clients, silently dropping other rows, either a list e.g. 1,5,9 or a file of ids
+    #[clap(long)]
+    allow_clients: Option<String>,
+
+    /// Largest amount allowed for a single deposit
+    #[clap(long, value_parser = parse_limit)]
+    max_deposit: Option<Decimal>,
+
+    /// Largest amount allowed for a single withdrawal
+    #[clap(long, value_parser = parse_limit)]
+    max_withdrawal: Option<Decimal>,
+
+    /// Whether a deposit or withdrawal over its limit is an error or ignored
+    #[clap(long, value_enum, default_value = "error")]
+    over_limit: OverLimit,
+
+    /// Warn on stderr when a dispute leaves more than this fraction of a client's funds held, e.g. 0.5
+    #[clap(long)]
+    held_ratio_alert: Option<Decimal>,
+
+    /// Write locked accounts first, then the rest, each sorted by client id
+    #[clap(long, conflicts_with = "low-memory")]
+    group_by_lock: bool,
+
+    /// Only write the first this many client rows, for a quick look at a large result
+    #[clap(long, conflicts_with_all = &["tail", "low-memory", "output-parts"])]
+    head: Option<usize>,
+
+    /// Only write the last this many client rows
+    #[clap(long, conflicts_with_all = &["low-memory", "output-parts"])]
+    tail: Option<usize>,
+
+    /// fsync --job and --output-parts files and their directory once written, for durability against power loss
+    #[clap(long)]
+    fsync: bool,
+
+    /// List to stderr clients that appeared in the input but have no recorded transactions and a zero total
+    #[clap(long, conflicts_with_all = &["low-memory", "jobs"])]
+    report_empty_clients: bool,
+
+    /// List to stderr disputes still open at the end, with how many of the client's transactions came after each
+    #[clap(long, conflicts_with_all = &["low-memory", "jobs"])]
+    report_open_disputes: bool,
+
+    /// CSV of client,available starting balances, credited before the transactions and not disputable
+    #[clap(long, conflicts_with = "jobs")]
+    opening: Option<String>,
+
+    /// CSV of currency,rate, how much of the --report-currency one unit of each asset is worth
+    #[clap(
+        long,
+        requires = "report-currency",
+        conflicts_with_all = &["jobs", "low-memory", "with-flows", "lock-reasons"]
+    )]
+    rates: Option<String>,
+
+    /// Write each client's assets converted with --rates and summed into this one currency
+    #[clap(long, requires = "rates")]
+    report_currency: Option<String>,
+
+    /// Write a row for every client in every --currencies asset, zero where it had none
+    #[clap(long, requires = "currencies", conflicts_with_all = &["jobs", "low-memory"])]
+    full_matrix: bool,
+
+    /// Comma separated assets for --full-matrix, e.g. USD,EUR
+    #[clap(long, value_delimiter = ',', requires = "full-matrix")]
+    currencies: Vec<String>,
+
+    /// Print each asset's available and held summed over all clients to stderr
+    #[clap(long, conflicts_with_all = &["jobs", "low-memory"])]
+    currency_totals: bool,
+
+    /// Also write every recorded deposit and withdrawal, with whether it is disputed, to this CSV
+    #[clap(long, conflicts_with_all = &["jobs", "low-memory"])]
+    dump_records: Option<String>,
+
+    /// Sort the output rows by this column, ties in client id order
+    #[clap(long, value_enum, default_value = "id", conflicts_with = "low-memory")]
+    sort_by: SortBy,
+
+    /// Sort the output rows largest first
+    #[clap(long, conflicts_with = "low-memory")]
+    desc: bool,
+
+    /// Write only the client, total and locked columns
+    #[clap(long, conflicts_with_all = &["with-flows", "lock-reasons"])]
+    totals_only: bool,
 }
 
-fn print_headers() {
-    println!("client,available,held,total,locked");
+/// Parse an INPUT=OUTPUT pair
+fn parse_job(s: &str) -> Result<(String, String), String> {
+    let (input, output) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected INPUT=OUTPUT, got {}", s))?;
+    Ok((input.to_string(), output.to_string()))
 }
 
-async fn process_csv(input: String) -> Result<Clients, Error> {
-    let mut rdr = ReaderBuilder::new().trim(Trim::All).from_path(input)?;
+/// Parse an ASSET=DP pair
+fn parse_asset_dp(s: &str) -> Result<(AssetId, u32), String> {
+    let (asset, dp) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ASSET=DP, got {}", s))?;
+    let dp = dp
+        .parse()
+        .map_err(|e| format!("invalid dp {}: {}", dp, e))?;
+    Ok((AssetId(asset.to_string()), dp))
+}
 
-    let valid_headers = HashSet::from(["type", "client", "tx", "amount"]);
-    for h in rdr.headers()? {
-        if !valid_headers.contains(h) {
-            bail!("Invalid header {}", h);
-        }
+fn parse_limit(s: &str) -> Result<Decimal, String> {
+    match s.parse::<Decimal>() {
+        Ok(limit) if limit > Decimal::ZERO => Ok(limit),
+        Ok(limit) => Err(format!("limit must be positive, got {}", limit)),
+        Err(e) => Err(format!("invalid limit {}: {}", s, e)),
     }
+}
 
-    // size number of shards based on cpu count
-    let num_shards: u16 = min(num_cpus::get(), u16::MAX as usize) as u16;
-
-    let mut shard_futs = Vec::with_capacity(num_shards.into());
-
-    let mut shard_handles = Vec::with_capacity(num_shards.into());
-    {
-        // Spawn the worker shards, channel per shard
-        for _i in 0..num_shards {
-            let (tx, mut rx) = mpsc::channel(SHARD_QUEUE_MAX);
-            shard_handles.push(tx);
-            shard_futs.push(tokio::spawn(async move {
-                let mut shard = Clients::default();
-                while let Some(t) = rx.recv().await {
-                    shard.process(t)?;
-                }
-                Ok::<_, Error>(shard)
-            }));
-        }
+fn parse_scale(s: &str) -> Result<Decimal, String> {
+    match s.parse::<Decimal>() {
+        Ok(unit) if unit > Decimal::ZERO => Ok(unit),
+        Ok(unit) => Err(format!("scale must be positive, got {}", unit)),
+        Err(e) => Err(format!("invalid scale {}: {}", s, e)),
     }
+}
+
+fn parse_tran_type(s: &str) -> Result<TranType, String> {
+    TranType::from_name(s).ok_or_else(|| format!("unknown transaction type {}", s))
+}
 
-    // Read from the csv and send to the shards
-    let mut seen_tx = HashSet::new();
-    for result in rdr.deserialize() {
-        let t: transaction::Transaction = result?;
-        match t.tran_type {
-            TranType::Deposit | TranType::Withdrawal => {
-                if seen_tx.contains(&t.tx) {
-                    bail!("Reused transaction {}", t.tx.id());
-                }
-                seen_tx.insert(t.tx);
-            }
-            _ => (),
+/// Exit code when `--fail-on-any-lock` stops a run
+const LOCKED_EXIT_CODE: i32 = 3;
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() -> Result<(), Error> {
+    let result = run(Args::parse()).await;
+    if let Err(e) = &result {
+        if e.chain().any(|cause| cause.is::<AccountLocked>()) {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(LOCKED_EXIT_CODE);
         }
-        let shard_id = t.client.id() % num_shards;
-        shard_handles[shard_id as usize].send(t).await?;
     }
+    result
+}
+
+/// Data quality report of clients only referenced by ignored transactions
+fn report_empty_clients(clients: &Clients) {
+    let empty: Vec<String> = clients
+        .empty_clients()
+        .iter()
+        .map(|client| client.id().to_string())
+        .collect();
+    if !empty.is_empty() {
+        eprintln!(
+            "Clients with no successful transactions: {}",
+            empty.join(",")
+        );
+    }
+}
 
-    // Close the channels
-    shard_handles.clear();
+/// Diagnostic dump of the transaction records behind the balances
+fn dump_records(clients: &Clients, path: &str, opts: &OutputOptions) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path).with_context(|| format!("Creating {}", path))?);
+    clients.write_records(&mut out)?;
+    output::finish_file(out, Path::new(path), opts)
+}
 
-    // collect the results
-    let mut combined = Clients::default();
-    for one_shard in try_join_all(shard_futs).await? {
-        combined.combine(one_shard?)?;
+/// The clients consolidated into the reporting currency, if converting
+fn report_clients(
+    clients: Clients,
+    convert: &Option<(Rates, AssetId, u32)>,
+) -> Result<Clients, Error> {
+    match convert {
+        Some((rates, currency, dp)) => rates.consolidate(&clients, currency, *dp),
+        None => Ok(clients),
     }
+}
 
-    Ok(combined)
+/// Compliance report of disputes neither resolved nor charged back
+fn report_open_disputes(clients: &Clients) {
+    for open in clients.open_dispute_report() {
+        eprintln!(
+            "Open dispute: client {}, tx {}, amount {}, open for {} transactions",
+            open.client.id(),
+            open.tx.id(),
+            open.amount,
+            open.age
+        );
+    }
 }
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), Error> {
-    let args = Args::parse();
+/// System totals per asset, the implicit asset shown as "none"
+fn report_currency_totals(clients: &Clients) -> Result<(), Error> {
+    for total in clients.currency_totals()? {
+        let asset = if total.asset.is_implicit() {
+            "none"
+        } else {
+            &total.asset.0
+        };
+        eprintln!(
+            "Currency {}: available {}, held {}",
+            asset, total.available, total.held
+        );
+    }
+    Ok(())
+}
 
-    let clients = process_csv(args.input).await?;
-    print_headers();
-    print!("{}", clients);
+async fn run(args: Args) -> Result<(), Error> {
+    if args.keep_minimum.is_sign_negative() {
+        bail!(
+            "--keep-minimum must not be negative, got {}",
+            args.keep_minimum
+        );
+    }
+
+    let parse_opts = ParseOptions {
+        allow_grouping: args.allow_grouping,
+        max_dp: args.max_dp,
+        asset_dp: args.asset_dp.into_iter().collect(),
+        allow_admin: args.allow_admin,
+        comment_prefix: args.comment_prefix,
+        control_type: args.control_type,
+        trim_trailing_zeros: args.trim_trailing_zeros,
+        batch: args.batch,
+        normalize_type_whitespace: args.normalize_type_whitespace,
+        assert_sorted: args.assert_sorted,
+        tx_scope: args.tx_scope,
+        expect_type: args.expect_type,
+    };
+
+    if args.print_schema {
+        print!("{}", schema::schema_text(&parse_opts));
+        return Ok(());
+    }
+
+    let out_opts = OutputOptions {
+        lock_reasons: args.lock_reasons,
+        zero_threshold: args.zero_threshold,
+        output_scale: args.output_scale,
+        mask_locked: args.mask_locked,
+        with_flows: args.with_flows,
+        group_by_lock: args.group_by_lock,
+        head: args.head,
+        tail: args.tail,
+        fsync: args.fsync,
+        totals_only: args.totals_only,
+        sort_by: args.sort_by,
+        desc: args.desc,
+    };
+
+    let opts = ProcessOptions {
+        // policy first, it reads the parse options before they move
+        policy: Policy {
+            strict_tx_semantics: args.strict_tx_semantics,
+            paranoid_max_dp: args.paranoid.then(|| parse_opts.finest_dp()),
+            keep_minimum: args.keep_minimum,
+            fail_on_lock: args.fail_on_any_lock,
+            max_dispute_cycles: args.max_dispute_cycles,
+            track_appeared: args.report_empty_clients,
+            track_disputes: args.report_open_disputes,
+            max_deposit: args.max_deposit,
+            max_withdrawal: args.max_withdrawal,
+            over_limit: args.over_limit,
+            held_ratio_alert: args.held_ratio_alert,
+            auto_release_after: args.auto_release_after,
+        },
+        parse: parse_opts,
+        combine: args.combine,
+        two_phase: args.two_phase,
+        shards: None,
+        channel: args.channel,
+        finalize: args.finalize_disputes,
+        require_output: args.require_output,
+        keep_good_shards: args.keep_good_shards,
+        skip_locked: args.skip_locked,
+        skipped_locked: Default::default(),
+        shard_hints: match &args.shard_hints {
+            Some(path) => ShardHints::from_path(path)?,
+            None => ShardHints::default(),
+        },
+        allow_clients: args
+            .allow_clients
+            .as_deref()
+            .map(AllowClients::parse)
+            .transpose()?,
+        expected_clients: args.expected_clients.unwrap_or_default(),
+        expected_transactions: args.expected_transactions.unwrap_or_default(),
+        per_shard_output: args.per_shard_output,
+        reorder_window: args.reorder_window,
+        max_input_bytes: args.max_input_bytes,
+        opening: match &args.opening {
+            Some(path) => OpeningBalances::from_path(path)?,
+            None => OpeningBalances::default(),
+        },
+    };
+
+    if !args.jobs.is_empty() {
+        return run_jobs(args.jobs, opts, out_opts).await;
+    }
+
+    let convert = match (&args.rates, args.report_currency) {
+        (Some(path), Some(currency)) => {
+            let currency = AssetId(currency);
+            let dp = opts.parse.max_dp_for(Some(&currency));
+            Some((Rates::from_path(path)?, currency, dp))
+        }
+        _ => None,
+    };
+
+    let input = match args.input {
+        Some(input) => input,
+        None => bail!("Input file required"),
+    };
+
+    if args.input_hash {
+        let digest = if input == "-" {
+            output::hash_reader(stdin().lock())?
+        } else {
+            output::hash_reader(open_input(&input)?)?
+        };
+        println!("{}", digest);
+        return Ok(());
+    }
+
+    if args.analyze {
+        eprintln!("{}", analyze_csv(&input, &opts.parse)?);
+        return Ok(());
+    }
+
+    let currencies: Vec<AssetId> = args.currencies.into_iter().map(AssetId).collect();
+    if let (Some(parts), Some(prefix)) = (args.output_parts, args.output_prefix) {
+        let mut clients = process_csv(input, opts).await?;
+        if args.report_empty_clients {
+            report_empty_clients(&clients);
+        }
+        if args.report_open_disputes {
+            report_open_disputes(&clients);
+        }
+        if args.currency_totals {
+            report_currency_totals(&clients)?;
+        }
+        if let Some(path) = &args.dump_records {
+            dump_records(&clients, path, &out_opts)?;
+        }
+        if args.full_matrix {
+            clients.fill_currencies(&currencies);
+        }
+        let clients = report_clients(clients, &convert)?;
+        partition::write_output_parts(&clients, &prefix, parts, &out_opts)?;
+        return Ok(());
+    }
+
+    let mut out = HashingWriter::new(BufWriter::new(stdout().lock()));
+    if args.low_memory {
+        process_csv_low_memory(input, opts, &mut out, &out_opts).await?;
+    } else {
+        let mut clients = process_csv(input, opts).await?;
+        if args.report_empty_clients {
+            report_empty_clients(&clients);
+        }
+        if args.report_open_disputes {
+            report_open_disputes(&clients);
+        }
+        if args.currency_totals {
+            report_currency_totals(&clients)?;
+        }
+        if let Some(path) = &args.dump_records {
+            dump_records(&clients, path, &out_opts)?;
+        }
+        if args.full_matrix {
+            clients.fill_currencies(&currencies);
+        }
+        report_clients(clients, &convert)?.write_to(&mut out, &out_opts)?;
+    }
+    out.flush()?;
+    if args.checksum {
+        eprintln!("sha256: {}", out.hex_digest());
+    }
     Ok(())
 }
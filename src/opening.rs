@@ -0,0 +1,108 @@
+use anyhow::{bail, Context, Error};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+use crate::clients::Clients;
+use crate::ids::{AssetId, ClientId};
+
+/// One row of an opening balances file
+#[derive(Debug, Deserialize)]
+struct OpeningRow {
+    client: ClientId,
+    available: Decimal,
+}
+
+/// Starting available balances, e.g. summarising earlier history.
+///
+/// They are credited directly with no transaction record, so unlike a deposit
+/// they can't be disputed.
+#[derive(Clone, Debug, Default)]
+pub struct OpeningBalances {
+    balances: HashMap<ClientId, Decimal>,
+}
+
+impl OpeningBalances {
+    /// Read `client,available` rows, a client listed twice or a negative
+    /// balance is an error
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("Opening balances {}", path))?;
+        let mut balances = HashMap::new();
+        for result in rdr.deserialize() {
+            let row: OpeningRow =
+                result.with_context(|| format!("Reading opening balances {}", path))?;
+            if row.available.is_sign_negative() {
+                bail!(
+                    "Client {} opening balance {} is negative",
+                    row.client.id(),
+                    row.available
+                );
+            }
+            if balances.insert(row.client, row.available).is_some() {
+                bail!(
+                    "Client {} has more than one opening balance",
+                    row.client.id()
+                );
+            }
+        }
+        Ok(Self { balances })
+    }
+
+    /// Credit the opening balances of the clients `mine` selects, e.g. those
+    /// routed to one shard. They are in the implicit asset.
+    pub fn seed(&self, clients: &mut Clients, mine: impl Fn(ClientId) -> bool) {
+        for (&client, &available) in &self.balances {
+            if mine(client) {
+                clients
+                    .balance_map
+                    .entry((client, AssetId::default()))
+                    .or_default()
+                    .open(available);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_opening_balances() -> Result<(), Error> {
+    use crate::clients::process_all;
+    use crate::ids::TxId;
+    use crate::testutil::write_test_csv;
+    use crate::transaction::{TranType, Transaction};
+    use rust_decimal_macros::dec;
+
+    let path = write_test_csv("opening.csv", "client,available\n1,10.5\n2, 0\n3,4\n")?;
+    let opening = OpeningBalances::from_path(&path);
+    std::fs::remove_file(&path)?;
+    let opening = opening?;
+
+    let mut clients = Clients::default();
+    opening.seed(&mut clients, |client| client != ClientId(3));
+    process_all(
+        &mut clients,
+        &[
+            Transaction::new(TranType::Withdrawal, ClientId(1), TxId(1), Some(dec!(3))),
+            // insufficient funds, ignored
+            Transaction::new(TranType::Withdrawal, ClientId(2), TxId(2), Some(dec!(1))),
+            Transaction::new(TranType::Deposit, ClientId(2), TxId(3), Some(dec!(2))),
+            Transaction::new(TranType::Withdrawal, ClientId(2), TxId(4), Some(dec!(1))),
+        ],
+    )?;
+    assert_eq!(clients.to_string(), "1,7.5,0,7.5,false\n2,1,0,1,false\n");
+
+    std::fs::write(&path, "client,available\n1,1\n1,2\n")?;
+    let err = OpeningBalances::from_path(&path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Client 1 has more than one opening balance"
+    );
+    std::fs::write(&path, "client,available\n1,-1\n")?;
+    let err = OpeningBalances::from_path(&path).unwrap_err();
+    std::fs::remove_file(&path)?;
+    assert_eq!(err.to_string(), "Client 1 opening balance -1 is negative");
+    Ok(())
+}
@@ -0,0 +1,524 @@
+use anyhow::{bail, Context, Error};
+use clap::ValueEnum;
+use rust_decimal::Decimal;
+use sha2::{Digest, Sha256};
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::balance::{Balance, OVERFLOW};
+use crate::ids::AccountId;
+
+/// Which column the output rows are sorted by
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum SortBy {
+    /// Client id, then asset
+    #[default]
+    Id,
+    /// Available plus held
+    Total,
+    Available,
+    Held,
+}
+
+/// Options controlling the output columns and formatting
+#[derive(Clone, Debug, Default)]
+pub struct OutputOptions {
+    /// Add a lock_reason column from the locking chargeback
+    pub lock_reasons: bool,
+    /// Print available or held within this of zero as 0, stored state is unchanged
+    pub zero_threshold: Option<Decimal>,
+    /// Print amounts as a whole number of this unit, e.g. 0.01 for cents
+    pub output_scale: Option<Decimal>,
+    /// Print a locked account's amounts as 0 so frozen funds aren't exposed
+    pub mask_locked: bool,
+    /// Add total_deposited and total_withdrawn columns, the gross flows
+    pub with_flows: bool,
+    /// Write the locked accounts first, then the rest, each by client id
+    pub group_by_lock: bool,
+    /// Only write the first this many client rows
+    pub head: Option<usize>,
+    /// Only write the last this many client rows
+    pub tail: Option<usize>,
+    /// fsync output files and their directory once written
+    pub fsync: bool,
+    /// Leave out the available and held columns, just the total and locked
+    pub totals_only: bool,
+    /// Sort the rows by this rather than client id, ties staying in client id order
+    pub sort_by: SortBy,
+    /// Sort largest first
+    pub desc: bool,
+}
+
+/// Write the CSV header row for the output columns, with an asset column
+/// after the client if `assets`
+pub fn write_header<W: Write>(w: &mut W, opts: &OutputOptions, assets: bool) -> Result<(), Error> {
+    write!(w, "client")?;
+    if assets {
+        write!(w, ",asset")?;
+    }
+    if opts.totals_only {
+        write!(w, ",total,locked")?;
+    } else {
+        write!(w, ",available,held,total,locked")?;
+    }
+    if opts.with_flows {
+        write!(w, ",total_deposited,total_withdrawn")?;
+    }
+    if opts.lock_reasons {
+        write!(w, ",lock_reason")?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Write one client's CSV row, for one asset if `assets`
+pub fn write_row<W: Write>(
+    w: &mut W,
+    (client, asset): &AccountId,
+    balance: &Balance,
+    opts: &OutputOptions,
+    assets: bool,
+) -> Result<(), Error> {
+    let fmt = |d: Decimal| match opts.output_scale {
+        Some(unit) => to_minor_units(d, unit),
+        None => Ok(d.to_string()),
+    };
+    write!(w, "{}", client.id())?;
+    if assets {
+        write!(w, ",{}", quote_field(&asset.0))?;
+    }
+    let masked = opts.mask_locked && balance.locked();
+    if masked && opts.totals_only {
+        write!(w, ",0,true")?;
+    } else if masked {
+        write!(w, ",0,0,0,true")?;
+    } else if opts.zero_threshold.is_none() && opts.output_scale.is_none() && !opts.totals_only {
+        write!(w, ",{}", balance)?;
+    } else {
+        let (mut available, mut held) = (balance.available(), balance.held());
+        if let Some(threshold) = opts.zero_threshold {
+            available = snap_to_zero(available, threshold);
+            held = snap_to_zero(held, threshold);
+        }
+        // total of what is shown, so the row still adds up
+        let total = match available.checked_add(held) {
+            Some(total) => fmt(total)?,
+            None => OVERFLOW.to_string(),
+        };
+        if opts.totals_only {
+            write!(w, ",{},{}", total, balance.locked())?;
+        } else {
+            write!(
+                w,
+                ",{},{},{},{}",
+                fmt(available)?,
+                fmt(held)?,
+                total,
+                balance.locked()
+            )?;
+        }
+    }
+    if opts.with_flows {
+        if masked {
+            write!(w, ",0,0")?;
+        } else {
+            let (deposited, withdrawn) = balance.flows();
+            for flow in [deposited, withdrawn] {
+                match flow {
+                    Some(flow) => write!(w, ",{}", fmt(flow)?)?,
+                    None => write!(w, ",{}", OVERFLOW)?,
+                }
+            }
+        }
+    }
+    if opts.lock_reasons {
+        write!(
+            w,
+            ",{}",
+            quote_field(balance.lock_reason().unwrap_or_default())
+        )?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Flush a finished output file, and with `fsync` sync it and its
+/// directory so both the contents and the file's entry survive power loss
+pub fn finish_file(out: BufWriter<File>, path: &Path, opts: &OutputOptions) -> Result<(), Error> {
+    let file = out
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .with_context(|| format!("Writing {}", path.display()))?;
+    if opts.fsync {
+        file.sync_all()
+            .with_context(|| format!("Syncing {}", path.display()))?;
+        // directories can only be opened for syncing on unix
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("Syncing {}", dir.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Passes writes through while hashing them, so the digest covers exactly
+/// the bytes written
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Hex SHA-256 of everything written so far
+    pub fn hex_digest(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hex SHA-256 of the raw bytes read, e.g. to tell whether an input has
+/// changed without processing it
+pub fn hash_reader<R: Read>(mut reader: R) -> Result<String, Error> {
+    let mut w = HashingWriter::new(std::io::sink());
+    std::io::copy(&mut reader, &mut w)?;
+    Ok(w.hex_digest())
+}
+
+/// Express an amount as an integer count of `unit`, erroring rather than
+/// rounding if it isn't an exact multiple
+fn to_minor_units(d: Decimal, unit: Decimal) -> Result<String, Error> {
+    if unit <= Decimal::ZERO {
+        bail!("invalid output scale {}", unit);
+    }
+    match d.checked_div(unit) {
+        Some(units) if units.fract().is_zero() => Ok(units.normalize().to_string()),
+        Some(_) => bail!("{} is not a whole number of {}", d, unit),
+        None => bail!("overflow scaling {} to {}", d, unit),
+    }
+}
+
+/// Treat amounts within the threshold of zero as rounding noise
+fn snap_to_zero(d: Decimal, threshold: Decimal) -> Decimal {
+    if d.abs() <= threshold {
+        Decimal::ZERO
+    } else {
+        d
+    }
+}
+
+/// Quote a free text output field if it would otherwise break the CSV
+pub fn quote_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[test]
+fn test_zero_threshold() -> Result<(), Error> {
+    use crate::ids::{AssetId, ClientId, TxId};
+    use rust_decimal_macros::dec;
+
+    let mut balance = Balance::default();
+    balance.deposit(TxId(1), dec!(5.0001))?;
+    balance.deposit(TxId(2), dec!(3))?;
+    balance.withdraw(TxId(3), dec!(5), Decimal::ZERO)?;
+    balance.dispute(TxId(2), None)?;
+    assert_eq!(balance.available(), dec!(0.0001));
+
+    let mut out = Vec::new();
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &OutputOptions::default(),
+        false,
+    )?;
+    assert_eq!(String::from_utf8(out)?, "1,0.0001,3,3.0001,false\n");
+
+    let opts = OutputOptions {
+        zero_threshold: Some(dec!(0.001)),
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &opts,
+        false,
+    )?;
+    assert_eq!(String::from_utf8(out)?, "1,0,3,3,false\n");
+
+    // only the output is snapped
+    assert_eq!(balance.available(), dec!(0.0001));
+
+    // negative noise snaps too, larger amounts don't
+    assert_eq!(snap_to_zero(dec!(-0.0005), dec!(0.001)), Decimal::ZERO);
+    assert_eq!(snap_to_zero(dec!(0.002), dec!(0.001)), dec!(0.002));
+    Ok(())
+}
+
+#[test]
+fn test_hashing_writer() -> Result<(), Error> {
+    let mut w = HashingWriter::new(Vec::new());
+    writeln!(w, "client,available,held,total,locked")?;
+    writeln!(w, "1,1.0,0,1.0,false")?;
+    assert_eq!(w.hex_digest(), format!("{:x}", Sha256::digest(&w.inner)));
+
+    // empty input has the well known empty digest
+    let w = HashingWriter::new(Vec::new());
+    assert_eq!(
+        w.hex_digest(),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_hash_reader() -> Result<(), Error> {
+    use crate::testutil::write_test_csv;
+
+    let contents = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    let paths = [
+        write_test_csv("hash-a.csv", contents)?,
+        write_test_csv("hash-b.csv", contents)?,
+        write_test_csv("hash-c.csv", contents.replace("1.0", "1.00"))?,
+    ];
+    let digests = paths
+        .iter()
+        .map(|path| hash_reader(File::open(path)?))
+        .collect::<Result<Vec<_>, Error>>();
+    for path in &paths {
+        std::fs::remove_file(path)?;
+    }
+    let digests = digests?;
+    assert_eq!(digests[0], digests[1]);
+    assert_ne!(digests[0], digests[2]);
+    assert_eq!(
+        digests[0],
+        format!("{:x}", Sha256::digest(contents.as_bytes()))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_output_scale() -> Result<(), Error> {
+    use crate::ids::{AssetId, ClientId, TxId};
+    use rust_decimal_macros::dec;
+
+    assert_eq!(to_minor_units(dec!(1.50), dec!(0.01))?, "150");
+    assert_eq!(to_minor_units(dec!(0), dec!(0.01))?, "0");
+    assert_eq!(to_minor_units(dec!(-2.5), dec!(0.5))?, "-5");
+    assert_eq!(to_minor_units(dec!(3), dec!(1))?, "3");
+    assert!(to_minor_units(dec!(1.505), dec!(0.01)).is_err());
+    assert!(to_minor_units(dec!(1), dec!(0)).is_err());
+
+    let mut balance = Balance::default();
+    balance.deposit(TxId(1), dec!(1.50))?;
+    balance.deposit(TxId(2), dec!(0.25))?;
+    balance.dispute(TxId(2), None)?;
+    let opts = OutputOptions {
+        output_scale: Some(dec!(0.01)),
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &opts,
+        false,
+    )?;
+    assert_eq!(String::from_utf8(out)?, "1,150,25,175,false\n");
+
+    // a sub cent amount can't be written as whole cents
+    balance.deposit(TxId(3), dec!(0.001))?;
+    assert!(write_row(
+        &mut Vec::new(),
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &opts,
+        false
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_mask_locked() -> Result<(), Error> {
+    use crate::ids::{AssetId, ClientId, TxId};
+    use rust_decimal_macros::dec;
+
+    let mut locked = Balance::default();
+    locked.deposit(TxId(1), dec!(5.0))?;
+    locked.deposit(TxId(2), dec!(2.0))?;
+    locked.dispute(TxId(2), None)?;
+    locked.chargeback(TxId(2), Some("fraud".to_string()))?;
+    let mut unlocked = Balance::default();
+    unlocked.deposit(TxId(3), dec!(1.5))?;
+
+    let opts = OutputOptions {
+        mask_locked: true,
+        lock_reasons: true,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &locked,
+        &opts,
+        false,
+    )?;
+    write_row(
+        &mut out,
+        &(ClientId(2), AssetId::default()),
+        &unlocked,
+        &opts,
+        false,
+    )?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "1,0,0,0,true,fraud\n2,1.5,0,1.5,false,\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_with_flows() -> Result<(), Error> {
+    use crate::ids::{AssetId, ClientId, TxId};
+    use rust_decimal_macros::dec;
+
+    let mut balance = Balance::default();
+    balance.deposit(TxId(1), dec!(10))?;
+    balance.withdraw(TxId(2), dec!(3), Decimal::ZERO)?;
+    let opts = OutputOptions {
+        with_flows: true,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    write_header(&mut out, &opts, false)?;
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &opts,
+        false,
+    )?;
+
+    // disputes move funds between available and held, the flows stay put
+    balance.dispute(TxId(1), None)?;
+    balance.dispute(TxId(2), None)?;
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &opts,
+        false,
+    )?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "client,available,held,total,locked,total_deposited,total_withdrawn
+1,7,0,7,false,10,3
+1,-3,7,4,false,10,3
+"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_totals_only() -> Result<(), Error> {
+    use crate::ids::{AssetId, ClientId, TxId};
+    use rust_decimal_macros::dec;
+
+    let mut balance = Balance::default();
+    balance.deposit(TxId(1), dec!(5.25))?;
+    balance.deposit(TxId(2), dec!(2))?;
+    balance.dispute(TxId(2), None)?;
+    let mut locked = Balance::default();
+    locked.deposit(TxId(3), dec!(1))?;
+    locked.dispute(TxId(3), None)?;
+    locked.chargeback(TxId(3), None)?;
+
+    let mut opts = OutputOptions {
+        totals_only: true,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    write_header(&mut out, &opts, false)?;
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &opts,
+        false,
+    )?;
+    write_row(
+        &mut out,
+        &(ClientId(2), AssetId::default()),
+        &locked,
+        &opts,
+        false,
+    )?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "client,total,locked\n1,7.25,false\n2,0,true\n"
+    );
+
+    // the total is still in the scaled units
+    opts.output_scale = Some(dec!(0.01));
+    let mut out = Vec::new();
+    write_row(
+        &mut out,
+        &(ClientId(1), AssetId::default()),
+        &balance,
+        &opts,
+        false,
+    )?;
+    assert_eq!(String::from_utf8(out)?, "1,725,false\n");
+    Ok(())
+}
+
+#[test]
+fn test_finish_file() -> Result<(), Error> {
+    let path = crate::testutil::test_path("fsync.csv");
+    let opts = OutputOptions {
+        fsync: true,
+        ..Default::default()
+    };
+    let mut out = BufWriter::new(File::create(&path)?);
+    write_header(&mut out, &opts, false)?;
+    finish_file(out, Path::new(&path), &opts)?;
+    let written = std::fs::read_to_string(&path);
+    std::fs::remove_file(&path)?;
+    assert_eq!(written?, "client,available,held,total,locked\n");
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Error};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::clients::Clients;
+use crate::ids::ClientId;
+use crate::output::{finish_file, write_header, write_row, OutputOptions};
+
+/// Write one shard's sorted client rows to a file, freeing its map. The rows
+/// always have an asset column, as another shard may have assets, so this
+/// returns whether this one did along with the path.
+pub fn write_partition(
+    clients: Clients,
+    path: PathBuf,
+    opts: &OutputOptions,
+) -> Result<(PathBuf, bool), Error> {
+    let mut out = BufWriter::new(
+        File::create(&path).with_context(|| format!("Creating {}", path.display()))?,
+    );
+    clients.write_rows(&mut out, opts, true)?;
+    out.flush()?;
+    Ok((path, clients.has_assets()))
+}
+
+/// Write one shard's clients, sorted and with a header, to
+/// `<prefix>-shard-<n>.csv` to see how clients were routed
+pub fn write_shard_output(
+    clients: &Clients,
+    prefix: &str,
+    shard_id: usize,
+) -> Result<PathBuf, Error> {
+    let path = PathBuf::from(format!("{}-shard-{}.csv", prefix, shard_id));
+    let mut out = BufWriter::new(
+        File::create(&path).with_context(|| format!("Creating {}", path.display()))?,
+    );
+    clients.write_to(&mut out, &OutputOptions::default())?;
+    out.flush()?;
+    Ok(path)
+}
+
+/// Client id of a partition row, used as the merge key
+fn client_of(record: &StringRecord) -> Result<u16, Error> {
+    Ok(record.get(0).unwrap_or_default().parse()?)
+}
+
+/// Merge sorted partition files into one sorted output with a header,
+/// holding only one row per partition in memory at a time. The partitions'
+/// asset column is dropped unless `assets`.
+pub fn merge_partitions<W: Write>(
+    paths: &[PathBuf],
+    w: &mut W,
+    opts: &OutputOptions,
+    assets: bool,
+) -> Result<(), Error> {
+    let mut readers = Vec::with_capacity(paths.len());
+    for path in paths {
+        readers.push(
+            ReaderBuilder::new()
+                .has_headers(false)
+                .from_path(path)
+                .with_context(|| format!("Opening {}", path.display()))?
+                .into_records(),
+        );
+    }
+
+    // Prime the heap with the first row of each partition
+    let mut heads = Vec::with_capacity(readers.len());
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        let head = reader.next().transpose()?;
+        if let Some(record) = head.as_ref() {
+            heap.push(Reverse((client_of(record)?, i)));
+        }
+        heads.push(head);
+    }
+
+    write_header(w, opts, assets)?;
+    let mut wtr = WriterBuilder::new().from_writer(w);
+    while let Some(Reverse((_, i))) = heap.pop() {
+        match heads[i].take() {
+            Some(record) if assets => wtr.write_record(&record)?,
+            Some(record) => wtr.write_record(
+                record
+                    .iter()
+                    .enumerate()
+                    .filter(|(column, _)| *column != 1)
+                    .map(|(_, field)| field),
+            )?,
+            None => (),
+        }
+        heads[i] = readers[i].next().transpose()?;
+        if let Some(record) = heads[i].as_ref() {
+            heap.push(Reverse((client_of(record)?, i)));
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Best effort removal of partition files
+pub fn remove_partitions(paths: &[PathBuf]) {
+    for path in paths {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Where to put a shard's partition file
+pub fn partition_path(dir: &Path, prefix: &str, shard_id: usize) -> PathBuf {
+    dir.join(format!("{}-{}.csv", prefix, shard_id))
+}
+
+/// Which of `parts` contiguous client id ranges a client falls in
+pub fn output_part(client: ClientId, parts: u16) -> usize {
+    client.id() as usize * parts as usize / (u16::MAX as usize + 1)
+}
+
+/// Write the output split into `parts` files by client id range, named
+/// `<prefix>-<n>.csv`, each with a header even if it has no clients
+pub fn write_output_parts(
+    clients: &Clients,
+    prefix: &str,
+    parts: u16,
+    opts: &OutputOptions,
+) -> Result<Vec<PathBuf>, Error> {
+    let assets = clients.has_assets();
+    if opts.output_scale.is_some() {
+        // an amount that isn't a whole unit errors before any file is created
+        for (account, balance) in clients.iter_output(opts) {
+            write_row(&mut std::io::sink(), account, balance, opts, assets)?;
+        }
+    }
+    let mut paths = Vec::with_capacity(parts.into());
+    let mut outs = Vec::with_capacity(parts.into());
+    for i in 0..parts {
+        let path = PathBuf::from(format!("{}-{}.csv", prefix, i));
+        let mut out = BufWriter::new(
+            File::create(&path).with_context(|| format!("Creating {}", path.display()))?,
+        );
+        write_header(&mut out, opts, assets)?;
+        paths.push(path);
+        outs.push(out);
+    }
+    for (account, balance) in clients.iter_output(opts) {
+        write_row(
+            &mut outs[output_part(account.0, parts)],
+            account,
+            balance,
+            opts,
+            assets,
+        )?;
+    }
+    for (out, path) in outs.into_iter().zip(&paths) {
+        finish_file(out, path, opts)?;
+    }
+    Ok(paths)
+}
+
+#[test]
+fn test_merge_partitions() -> Result<(), Error> {
+    use crate::ids::{AssetId, ClientId, TxId};
+    use crate::transaction::{TranType, Transaction};
+    use rust_decimal_macros::dec;
+
+    let opts = OutputOptions {
+        lock_reasons: true,
+        ..Default::default()
+    };
+
+    for asset in [None, Some(AssetId("BTC".to_string()))] {
+        // three shards routed by client % 3, as the real run would
+        let mut shards: Vec<Clients> = (0..3).map(|_| Clients::default()).collect();
+        let mut all = Clients::default();
+        for (client, tx) in [(5, 1), (1, 2), (3, 3), (4, 4), (0, 5), (2, 6), (7, 7)] {
+            let t = Transaction::new(
+                TranType::Deposit,
+                ClientId(client),
+                TxId(tx),
+                Some(dec!(1.5)),
+            );
+            shards[client as usize % 3].process(t.clone())?;
+            all.process(t)?;
+        }
+        for clients in [&mut shards[1], &mut all] {
+            clients.process(Transaction::new(
+                TranType::Dispute,
+                ClientId(4),
+                TxId(4),
+                None,
+            ))?;
+            clients.process(
+                Transaction::new(TranType::Chargeback, ClientId(4), TxId(4), None)
+                    .with_reason(Some("comma, in reason".to_string())),
+            )?;
+            // a second asset for one client in one shard only
+            clients.process(
+                Transaction::new(TranType::Deposit, ClientId(4), TxId(8), Some(dec!(2)))
+                    .with_asset(asset.clone()),
+            )?;
+        }
+
+        let dir = std::env::temp_dir();
+        let prefix = format!("paytoy-{}-test-merge", std::process::id());
+        let mut paths = Vec::new();
+        let mut assets = false;
+        for (i, shard) in shards.into_iter().enumerate() {
+            let (path, shard_assets) =
+                write_partition(shard, partition_path(&dir, &prefix, i), &opts)?;
+            paths.push(path);
+            assets |= shard_assets;
+        }
+        assert_eq!(assets, asset.is_some());
+
+        let mut merged = Vec::new();
+        merge_partitions(&paths, &mut merged, &opts, assets)?;
+        remove_partitions(&paths);
+
+        let mut expected = Vec::new();
+        all.write_to(&mut expected, &opts)?;
+        assert_eq!(String::from_utf8(merged)?, String::from_utf8(expected)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write_output_parts() -> Result<(), Error> {
+    use crate::ids::TxId;
+    use crate::transaction::{TranType, Transaction};
+    use rust_decimal_macros::dec;
+
+    assert_eq!(output_part(ClientId(0), 4), 0);
+    assert_eq!(output_part(ClientId(16383), 4), 0);
+    assert_eq!(output_part(ClientId(16384), 4), 1);
+    assert_eq!(output_part(ClientId(u16::MAX), 4), 3);
+    assert_eq!(output_part(ClientId(u16::MAX), 1), 0);
+
+    let mut clients = Clients::default();
+    for (client, tx) in [(40000, 1), (1, 2), (20000, 3), (2, 4)] {
+        clients.process(Transaction::new(
+            TranType::Deposit,
+            ClientId(client),
+            TxId(tx),
+            Some(dec!(1.5)),
+        ))?;
+    }
+
+    let prefix = crate::testutil::test_path("test-parts");
+    let paths = write_output_parts(&clients, &prefix, 4, &OutputOptions::default())?;
+    let contents = paths
+        .iter()
+        .map(std::fs::read_to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    remove_partitions(&paths);
+
+    assert_eq!(paths[3], PathBuf::from(format!("{}-3.csv", prefix)));
+    let header = "client,available,held,total,locked\n";
+    assert_eq!(
+        contents,
+        vec![
+            format!("{}1,1.5,0,1.5,false\n2,1.5,0,1.5,false\n", header),
+            format!("{}20000,1.5,0,1.5,false\n", header),
+            format!("{}40000,1.5,0,1.5,false\n", header),
+            header.to_string(),
+        ]
+    );
+    Ok(())
+}
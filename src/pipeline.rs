@@ -0,0 +1,1468 @@
+use anyhow::{anyhow, bail, Context, Error};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
+
+use futures::future::{join_all, try_join_all};
+use futures::stream::{self, Stream, StreamExt};
+
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Chain, Cursor, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::task::JoinError;
+
+use crate::allow::AllowClients;
+use crate::analyze::ShardLoad;
+use crate::balance::Policy;
+use crate::channel::{self, ChannelKind};
+use crate::clients::{Clients, CombineMode, FinalizeMode};
+use crate::control::ControlCheck;
+use crate::dedup::{SeenTx, TxOrder};
+use crate::engine::Engine;
+use crate::hints::ShardHints;
+use crate::locked::lock_channel;
+use crate::opening::OpeningBalances;
+use crate::output::{self, OutputOptions};
+use crate::partition;
+use crate::reorder::ReorderBuffer;
+use crate::transaction::{ParseOptions, RawTransaction, TranType, Transaction, VALID_HEADERS};
+
+const SHARD_QUEUE_MAX: usize = 1_000_000;
+
+/// Options for a processing run
+#[derive(Clone, Debug, Default)]
+pub struct ProcessOptions {
+    pub parse: ParseOptions,
+    pub combine: CombineMode,
+    /// Validate the whole input before processing any of it, needs a file
+    pub two_phase: bool,
+    /// Number of shards, defaults to the cpu count
+    pub shards: Option<u16>,
+    pub policy: Policy,
+    pub channel: ChannelKind,
+    pub finalize: FinalizeMode,
+    /// Error rather than output no clients
+    pub require_output: bool,
+    pub shard_hints: ShardHints,
+    /// Carry on without a shard that fails, warning, rather than failing the run
+    pub keep_good_shards: bool,
+    /// Drop transactions for clients already locked before queueing them.
+    /// Not for use with strict tx semantics, whose errors it could hide.
+    pub skip_locked: bool,
+    /// Counts the transactions `skip_locked` dropped, shared with clones so
+    /// the caller can read it after the run
+    pub skipped_locked: Arc<AtomicU64>,
+    /// Available balances credited before the first transaction
+    pub opening: OpeningBalances,
+    /// Only process these clients, dropping other rows before dispatch
+    pub allow_clients: Option<AllowClients>,
+    /// Pre-size the client maps for this many clients across all shards
+    pub expected_clients: usize,
+    /// Pre-size the tx id set for this many deposits and withdrawals
+    pub expected_transactions: usize,
+    /// Also write each shard's clients to <prefix>-shard-<n>.csv before combining
+    pub per_shard_output: Option<String>,
+    /// Apply each shard's transactions in timestamp order within a window of this many
+    pub reorder_window: Option<usize>,
+    /// Refuse to start on an input file larger than this many bytes
+    pub max_input_bytes: Option<u64>,
+}
+
+/// The input from its header line on, that line having been read while
+/// looking for it and put back in front of the rest
+type Input<R> = Chain<Cursor<Vec<u8>>, BufReader<R>>;
+
+/// Catch UTF-16 input up front, otherwise it shows up as a confusing
+/// invalid header
+fn check_encoding(start: &[u8]) -> Result<(), Error> {
+    if start.starts_with(&[0xFF, 0xFE]) || start.starts_with(&[0xFE, 0xFF]) {
+        bail!("input appears to be UTF-16, expected UTF-8");
+    }
+    Ok(())
+}
+
+/// Check the encoding and skip leading metadata lines starting with
+/// `prefix`, returning the input from the header on. Positions in later
+/// errors count from there.
+fn skip_preamble<R: Read>(reader: R, prefix: &str) -> Result<Input<R>, Error> {
+    let mut rdr = BufReader::new(reader);
+    let mut line = Vec::new();
+    rdr.read_until(b'\n', &mut line)?;
+    check_encoding(&line)?;
+    while !prefix.is_empty() && line.starts_with(prefix.as_bytes()) {
+        line.clear();
+        rdr.read_until(b'\n', &mut line)?;
+    }
+    Ok(Cursor::new(line).chain(rdr))
+}
+
+/// Open the input file, with a short reason if that fails
+pub fn open_input(input: &str) -> Result<File, Error> {
+    File::open(input).map_err(|e| {
+        let hint = match e.kind() {
+            ErrorKind::NotFound => "not found".to_string(),
+            ErrorKind::PermissionDenied => "permission denied".to_string(),
+            _ => e.to_string(),
+        };
+        anyhow!("failed to open input file '{}': {}", input, hint)
+    })
+}
+
+/// Refuse an input file over `max` bytes before reading any of it
+fn check_input_size(input: &str, max: Option<u64>) -> Result<(), Error> {
+    let max = match max {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+    let len = open_input(input)?.metadata()?.len();
+    if len > max {
+        bail!(
+            "input file '{}' is {} bytes, over the {} byte limit",
+            input,
+            len,
+            max
+        );
+    }
+    Ok(())
+}
+
+/// Start reading CSV, skipping any leading comment lines, and check its headers
+fn csv_reader<R: Read>(
+    reader: R,
+    opts: &ParseOptions,
+) -> Result<(Reader<Input<R>>, StringRecord), Error> {
+    let input = skip_preamble(reader, &opts.comment_prefix)?;
+    let mut rdr = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+
+    let valid_headers = HashSet::from(VALID_HEADERS);
+    let headers = rdr.headers()?.clone();
+    if headers.is_empty() {
+        bail!("Empty input, missing header row");
+    }
+    for h in &headers {
+        if !valid_headers.contains(h) {
+            bail!("Invalid header {}", h);
+        }
+    }
+    if opts.batch.is_some() && !headers.iter().any(|h| h == "batch") {
+        bail!("No batch column to pick a batch from");
+    }
+    Ok((rdr, headers))
+}
+
+/// Open the input file and check its headers, as `csv_reader`
+fn open_csv(
+    input: &str,
+    opts: &ParseOptions,
+) -> Result<(Reader<Input<File>>, StringRecord), Error> {
+    csv_reader(open_input(input)?, opts)
+}
+
+/// Overrides the shard count for tests, so they behave the same on any machine
+const SHARDS_ENV: &str = "PAYTOY_SHARDS";
+
+/// size number of shards based on cpu count, unless set by `PAYTOY_SHARDS`
+fn num_shards() -> Result<u16, Error> {
+    shard_count(std::env::var(SHARDS_ENV).ok().as_deref())
+}
+
+/// The shard count for the value of `PAYTOY_SHARDS`, the cpu count if unset
+fn shard_count(var: Option<&str>) -> Result<u16, Error> {
+    match var {
+        Some(v) => parse_shards(v),
+        None => Ok(min(num_cpus::get(), u16::MAX as usize) as u16),
+    }
+}
+
+fn parse_shards(v: &str) -> Result<u16, Error> {
+    match v.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("Invalid {} {}, expected 1 to {}", SHARDS_ENV, v, u16::MAX),
+    }
+}
+
+/// Scan the input and report how evenly the clients spread over the shards
+pub fn analyze_csv(input: &str, opts: &ParseOptions) -> Result<ShardLoad, Error> {
+    let (mut rdr, headers) = open_csv(input, opts)?;
+    let mut control = ControlCheck::new(opts, &headers);
+    let mut load = ShardLoad::new(num_shards()?);
+    for result in rdr.records() {
+        let record = result?;
+        if control.check(&record)? {
+            continue;
+        }
+        let raw = RawTransaction::from_record(&record, &headers, opts)?;
+        load.add(raw.client);
+    }
+    control.finish()?;
+    Ok(load)
+}
+
+/// Deserialize and validate one input row
+fn parse_record(
+    record: &StringRecord,
+    headers: &StringRecord,
+    opts: &ParseOptions,
+) -> Result<Transaction, Error> {
+    let raw = RawTransaction::from_record(record, headers, opts)?;
+    // worded as the csv crate's own deserialize errors were, when amounts
+    // were checked while deserializing
+    Transaction::from_raw(raw, opts).map_err(|e| {
+        let context = match record.position() {
+            Some(pos) => format!(
+                "CSV deserialize error: record {} (line: {}, byte: {}): {}",
+                pos.record(),
+                pos.line(),
+                pos.byte(),
+                e
+            ),
+            None => format!("CSV deserialize error: {}", e),
+        };
+        e.context(context)
+    })
+}
+
+/// First phase of a two phase run, check every row without computing any
+/// balances and report all the problems found rather than just the first.
+fn validate_csv(
+    input: &str,
+    opts: &ParseOptions,
+    allow: Option<&AllowClients>,
+) -> Result<(), Error> {
+    let (mut rdr, headers) = open_csv(input, opts)?;
+    let mut control = ControlCheck::new(opts, &headers);
+    let mut seen_tx = SeenTx::new(opts.tx_scope);
+    let mut order = opts.assert_sorted.then(TxOrder::default);
+    let mut problems = Vec::new();
+    for result in rdr.records() {
+        let checked = result.map_err(Error::from).and_then(|record| {
+            if control.check(&record)? {
+                return Ok(());
+            }
+            let t = parse_record(&record, &headers, opts)?;
+            if !opts.in_batch(&t) || matches!(allow, Some(allow) if !allow.allows(t.client)) {
+                return Ok(());
+            }
+            seen_tx.check(&t)?;
+            match &mut order {
+                Some(order) => order.check(&t),
+                None => Ok(()),
+            }
+        });
+        if let Err(e) = checked {
+            problems.push(format!("{:#}", e));
+        }
+    }
+    if let Err(e) = control.finish() {
+        problems.push(format!("{:#}", e));
+    }
+    if !problems.is_empty() {
+        bail!(
+            "{} invalid rows, nothing processed:\n{}",
+            problems.len(),
+            problems.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Read the input and run it through the client shards, calling `finish`
+/// in each shard task once it has processed all its transactions.
+async fn run_shards<T, F>(input: &str, opts: &ProcessOptions, finish: F) -> Result<Vec<T>, Error>
+where
+    T: Send + 'static,
+    F: Fn(usize, Clients) -> Result<T, Error> + Clone + Send + 'static,
+{
+    check_input_size(input, opts.max_input_bytes)?;
+    if opts.two_phase {
+        validate_csv(input, &opts.parse, opts.allow_clients.as_ref())?;
+    }
+    run_reader_shards(open_input(input)?, opts, finish).await
+}
+
+/// Run CSV from any reader through the client shards, as `run_shards`
+async fn run_reader_shards<R, T, F>(
+    reader: R,
+    opts: &ProcessOptions,
+    finish: F,
+) -> Result<Vec<T>, Error>
+where
+    R: Read,
+    T: Send + 'static,
+    F: Fn(usize, Clients) -> Result<T, Error> + Clone + Send + 'static,
+{
+    let (mut rdr, headers) = csv_reader(reader, &opts.parse)?;
+    let mut control = ControlCheck::new(&opts.parse, &headers);
+    let records = rdr.records().filter_map(|result| {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e.into())),
+        };
+        match control.check(&record) {
+            Ok(true) => None,
+            Ok(false) => match parse_record(&record, &headers, &opts.parse) {
+                // other batches are dropped before they use up tx ids
+                Ok(t) if !opts.parse.in_batch(&t) => None,
+                parsed => Some(parsed),
+            },
+            Err(e) => Some(Err(e)),
+        }
+    });
+    let results = run_stream_shards(stream::iter(records), opts, finish).await?;
+    control.finish()?;
+    Ok(results)
+}
+
+/// Run parsed transactions through the client shards, as `run_shards` but
+/// from any source
+async fn run_stream_shards<T, F, S>(
+    transactions: S,
+    opts: &ProcessOptions,
+    finish: F,
+) -> Result<Vec<T>, Error>
+where
+    T: Send + 'static,
+    F: Fn(usize, Clients) -> Result<T, Error> + Clone + Send + 'static,
+    S: Stream<Item = Result<Transaction, Error>>,
+{
+    let num_shards = match opts.shards {
+        Some(n) => n,
+        None => num_shards()?,
+    };
+
+    let mut shard_futs = Vec::with_capacity(num_shards.into());
+
+    let mut shard_handles = Vec::with_capacity(num_shards.into());
+    let (notifier, mut locked) = lock_channel();
+    let notifier = opts.skip_locked.then_some(notifier);
+    {
+        // Spawn the worker shards, channel per shard
+        for i in 0..num_shards {
+            let (tx, mut rx) = channel::channel(opts.channel, SHARD_QUEUE_MAX);
+            shard_handles.push(tx);
+            let mut shard = Clients::with_capacity(
+                opts.policy.clone(),
+                opts.expected_clients.div_ceil(num_shards.into()),
+            );
+            opts.opening.seed(&mut shard, |client| {
+                opts.shard_hints.shard_for(client, num_shards) == i
+                    && !matches!(&opts.allow_clients, Some(allow) if !allow.allows(client))
+            });
+            let finish = finish.clone();
+            let finalize = opts.finalize;
+            let notifier = notifier.clone();
+            let mut reorder = opts.reorder_window.map(ReorderBuffer::new);
+            shard_futs.push(tokio::spawn(async move {
+                // tx ids were checked before the transactions were sent
+                let mut engine = Engine::for_shard(shard);
+                let mut apply = |t: Transaction| -> Result<(), Error> {
+                    let chargeback = (t.tran_type == TranType::Chargeback).then(|| t.account());
+                    engine.apply(t)?;
+                    if let (Some(notifier), Some(account)) = (&notifier, chargeback) {
+                        if matches!(engine.clients().balance_map.get(&account), Some(b) if b.locked())
+                        {
+                            notifier.locked(account);
+                        }
+                    }
+                    Ok(())
+                };
+                while let Some(t) = rx.recv().await {
+                    match &mut reorder {
+                        Some(reorder) => {
+                            if let Some(t) = reorder.push(t)? {
+                                apply(t)?;
+                            }
+                        }
+                        None => apply(t)?,
+                    }
+                }
+                if let Some(reorder) = reorder {
+                    reorder.drain().try_for_each(&mut apply)?;
+                }
+                let mut shard = engine.into_clients();
+                shard.finalize_disputes(finalize)?;
+                finish(i.into(), shard)
+            }));
+        }
+    }
+
+    // Read from the source and send to the shards
+    let mut transactions = Box::pin(transactions);
+    let mut seen_tx = SeenTx::with_capacity(opts.parse.tx_scope, opts.expected_transactions);
+    let mut order = opts.parse.assert_sorted.then(TxOrder::default);
+    let mut send_err = None;
+    while let Some(t) = transactions.next().await {
+        let t = t?;
+        if matches!(&opts.allow_clients, Some(allow) if !allow.allows(t.client)) {
+            continue;
+        }
+        seen_tx.check(&t)?;
+        if let Some(order) = &mut order {
+            order.check(&t)?;
+        }
+        if notifier.is_some() && locked.skip(&t) {
+            opts.skipped_locked.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let shard_id = opts.shard_hints.shard_for(t.client, num_shards);
+        let sent = shard_handles[shard_id as usize].send(t).await;
+        // a failed shard has dropped its queue, its error is reported below
+        if let Err(e) = sent {
+            if !opts.keep_good_shards {
+                send_err = Some(e);
+                break;
+            }
+        }
+    }
+
+    // Close the channels
+    shard_handles.clear();
+
+    // collect the results, a shard's own error explains a failed send
+    if !opts.keep_good_shards {
+        let results = try_join_all(shard_futs)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<T>, Error>>()?;
+        return match send_err {
+            Some(e) => Err(e),
+            None => Ok(results),
+        };
+    }
+    let mut good = Vec::with_capacity(shard_futs.len());
+    for (i, result) in join_all(shard_futs).await.into_iter().enumerate() {
+        match result.map_err(shard_panic).and_then(|r| r) {
+            Ok(t) => good.push(t),
+            Err(e) => eprintln!(
+                "Warning: shard {} failed, its clients are missing: {:#}",
+                i, e
+            ),
+        }
+    }
+    Ok(good)
+}
+
+/// Turn a shard task panic, e.g. on arithmetic overflow, into its message
+fn shard_panic(e: JoinError) -> Error {
+    if !e.is_panic() {
+        return e.into();
+    }
+    let panic = e.into_panic();
+    match panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+    {
+        Some(msg) => anyhow!("panicked: {}", msg),
+        None => anyhow!("panicked"),
+    }
+}
+
+/// Combine the per shard results into one set of clients
+fn combine_shards(shards: Vec<Clients>, opts: &ProcessOptions) -> Result<Clients, Error> {
+    let mut combined = Clients::new(opts.policy.clone());
+    for one_shard in shards {
+        combined.combine(one_shard, opts.combine)?;
+    }
+    Ok(combined)
+}
+
+/// Process already parsed transactions from any async source, e.g. a queue
+/// or database cursor, through the same shards as the CSV input
+pub async fn process_stream<S>(transactions: S, opts: ProcessOptions) -> Result<Clients, Error>
+where
+    S: Stream<Item = Result<Transaction, Error>>,
+{
+    let shards = run_stream_shards(transactions, &opts, |_, shard| Ok(shard)).await?;
+    let combined = combine_shards(shards, &opts)?;
+    if opts.require_output && combined.balance_map.is_empty() {
+        bail!("No clients in stream, output would be empty");
+    }
+    Ok(combined)
+}
+
+/// Process CSV from any reader, e.g. stdin or a network stream, into the
+/// combined clients. Two phase processing needs a file and isn't supported.
+pub async fn process_reader<R: Read>(reader: R, opts: ProcessOptions) -> Result<Clients, Error> {
+    if opts.two_phase {
+        bail!("Two phase processing needs a file it can read twice");
+    }
+    if opts.max_input_bytes.is_some() {
+        eprintln!("Warning: input size unknown, not checking the max input bytes");
+    }
+    let combined = process_shards(reader, &opts).await?;
+    if opts.require_output && combined.balance_map.is_empty() {
+        bail!("No clients in input, output would be empty");
+    }
+    Ok(combined)
+}
+
+pub async fn process_csv(input: String, opts: ProcessOptions) -> Result<Clients, Error> {
+    check_input_size(&input, opts.max_input_bytes)?;
+    if opts.two_phase {
+        validate_csv(&input, &opts.parse, opts.allow_clients.as_ref())?;
+    }
+    let combined = process_shards(open_input(&input)?, &opts).await?;
+    if opts.require_output && combined.balance_map.is_empty() {
+        bail!("No clients in {}, output would be empty", input);
+    }
+
+    Ok(combined)
+}
+
+/// Run the reader through the shards, writing any per shard output, and
+/// combine them
+async fn process_shards<R: Read>(reader: R, opts: &ProcessOptions) -> Result<Clients, Error> {
+    let per_shard_output = opts.per_shard_output.clone();
+    let shards = run_reader_shards(reader, opts, move |shard_id, shard| {
+        if let Some(prefix) = &per_shard_output {
+            partition::write_shard_output(&shard, prefix, shard_id)?;
+        }
+        Ok(shard)
+    })
+    .await?;
+    combine_shards(shards, opts)
+}
+
+/// Output without combining the shards in memory. Each shard writes its
+/// sorted rows to a temporary file and frees its map as soon as it is done,
+/// then the files are merged into the output.
+///
+/// The shards still run at the same time, so every shard's map is live until
+/// the input is drained. This saves the combined map, not the peak while
+/// processing.
+pub async fn process_csv_low_memory<W: Write>(
+    input: String,
+    opts: ProcessOptions,
+    w: &mut W,
+    out_opts: &OutputOptions,
+) -> Result<(), Error> {
+    static RUN: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir();
+    let prefix = format!(
+        "paytoy-{}-{}",
+        std::process::id(),
+        RUN.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let finish = {
+        let dir = dir.clone();
+        let prefix = prefix.clone();
+        let out_opts = out_opts.clone();
+        move |shard_id, shard| {
+            partition::write_partition(
+                shard,
+                partition::partition_path(&dir, &prefix, shard_id),
+                &out_opts,
+            )
+        }
+    };
+    let (paths, assets): (Vec<_>, Vec<_>) = match run_shards(&input, &opts, finish).await {
+        Ok(written) => written.into_iter().unzip(),
+        Err(e) => {
+            // clean up after any shards that did finish
+            let num_shards = match opts.shards {
+                Some(n) => n,
+                None => num_shards()?,
+            };
+            let paths: Vec<_> = (0..num_shards.into())
+                .map(|i| partition::partition_path(&dir, &prefix, i))
+                .collect();
+            partition::remove_partitions(&paths);
+            return Err(e);
+        }
+    };
+
+    let merged = partition::merge_partitions(&paths, w, out_opts, assets.contains(&true));
+    partition::remove_partitions(&paths);
+    merged
+}
+
+/// Run independent input files concurrently, splitting the cpus between them
+pub async fn run_jobs(
+    jobs: Vec<(String, String)>,
+    opts: ProcessOptions,
+    out_opts: OutputOptions,
+) -> Result<(), Error> {
+    let shards = max(
+        1,
+        num_shards()? / jobs.len().clamp(1, u16::MAX as usize) as u16,
+    );
+    let mut job_futs = Vec::with_capacity(jobs.len());
+    for (input, output) in jobs {
+        let opts = ProcessOptions {
+            shards: Some(shards),
+            ..opts.clone()
+        };
+        let out_opts = out_opts.clone();
+        job_futs.push(tokio::spawn(async move {
+            let clients = process_csv(input.clone(), opts)
+                .await
+                .with_context(|| format!("Processing {}", input))?;
+            let mut out = BufWriter::new(
+                File::create(&output).with_context(|| format!("Creating {}", output))?,
+            );
+            clients.write_to(&mut out, &out_opts)?;
+            output::finish_file(out, Path::new(&output), &out_opts)?;
+            Ok::<_, Error>(())
+        }));
+    }
+    for job in try_join_all(job_futs).await? {
+        job?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use crate::testutil::write_test_csv;
+
+#[tokio::test]
+async fn test_two_phase() -> Result<(), Error> {
+    let input = write_test_csv(
+        "two_phase.csv",
+        "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,1.23456
+deposit,2,3,2.0
+deposit,2,1,1.0
+withdrawal,2,4,
+deposit,3,5,1.0
+",
+    )?;
+
+    let opts = ProcessOptions {
+        two_phase: true,
+        ..Default::default()
+    };
+    let err = process_csv(input.clone(), opts).await.unwrap_err();
+    let msg = err.to_string();
+    // all three problems reported, not just the first
+    assert!(
+        msg.starts_with("3 invalid rows, nothing processed:"),
+        "{}",
+        msg
+    );
+    assert!(msg.contains("line: 3"), "{}", msg);
+    assert!(msg.contains("Reused transaction 1"), "{}", msg);
+    assert!(msg.contains("line: 6"), "{}", msg);
+
+    // single phase stops at the first
+    let err = process_csv(input.clone(), ProcessOptions::default())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("line: 3"));
+
+    std::fs::remove_file(input)?;
+
+    // clean file processes the same either way
+    let input = write_test_csv(
+        "two_phase_clean.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\n",
+    )?;
+    let opts = ProcessOptions {
+        two_phase: true,
+        ..Default::default()
+    };
+    let clients = process_csv(input.clone(), opts).await?;
+    assert_eq!(clients.to_string(), "1,1.0,0,1.0,false\n");
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_jobs() -> Result<(), Error> {
+    let a = write_test_csv(
+        "job_a.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,0.5\n",
+    )?;
+    let b = write_test_csv(
+        "job_b.csv",
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,2.0\n",
+    )?;
+    // the same tx ids in each file are fine, the jobs are independent
+    let a_out = format!("{}.out", a);
+    let b_out = format!("{}.out", b);
+    run_jobs(
+        vec![(a.clone(), a_out.clone()), (b.clone(), b_out.clone())],
+        ProcessOptions::default(),
+        OutputOptions::default(),
+    )
+    .await?;
+    assert_eq!(
+        std::fs::read_to_string(&a_out)?,
+        "client,available,held,total,locked\n1,0.5,0,0.5,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&b_out)?,
+        "client,available,held,total,locked\n1,5.0,0,5.0,false\n2,2.0,0,2.0,false\n"
+    );
+    for f in [a, b, a_out, b_out] {
+        std::fs::remove_file(f)?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_low_memory() -> Result<(), Error> {
+    let input = write_test_csv(
+        "low_memory.csv",
+        "type,client,tx,amount
+deposit,7,1,1.0
+deposit,2,2,2.0
+deposit,9,3,3.0
+deposit,4,4,4.0
+deposit,1,5,5.0
+withdrawal,9,6,1.0
+dispute,4,4,
+",
+    )?;
+    let opts = ProcessOptions {
+        shards: Some(3),
+        ..Default::default()
+    };
+    let out_opts = OutputOptions::default();
+
+    let mut low = Vec::new();
+    process_csv_low_memory(input.clone(), opts.clone(), &mut low, &out_opts).await?;
+
+    let mut expected = Vec::new();
+    process_csv(input.clone(), opts)
+        .await?
+        .write_to(&mut expected, &out_opts)?;
+    assert_eq!(String::from_utf8(low)?, String::from_utf8(expected)?);
+
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_empty_input() -> Result<(), Error> {
+    let input = write_test_csv("empty.csv", "")?;
+    let err = process_csv(input.clone(), ProcessOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Empty input, missing header row");
+    std::fs::remove_file(input)?;
+
+    let input = write_test_csv("header_only.csv", "type,client,tx,amount\n")?;
+    let clients = process_csv(input.clone(), ProcessOptions::default()).await?;
+    let mut out = Vec::new();
+    clients.write_to(&mut out, &OutputOptions::default())?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "client,available,held,total,locked\n"
+    );
+
+    let opts = ProcessOptions {
+        require_output: true,
+        ..Default::default()
+    };
+    let err = process_csv(input.clone(), opts).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("No clients in {}, output would be empty", input)
+    );
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checksum_stable() -> Result<(), Error> {
+    use crate::output::HashingWriter;
+
+    let input = write_test_csv(
+        "checksum.csv",
+        "type,client,tx,amount
+deposit,3,1,1.0
+deposit,1,2,2.0
+deposit,2,3,3.0
+dispute,1,2,
+withdrawal,3,4,0.5
+",
+    )?;
+    let mut digests = Vec::new();
+    for _ in 0..5 {
+        let opts = ProcessOptions {
+            shards: Some(2),
+            ..Default::default()
+        };
+        let mut out = HashingWriter::new(Vec::new());
+        process_csv(input.clone(), opts)
+            .await?
+            .write_to(&mut out, &OutputOptions::default())?;
+        digests.push(out.hex_digest());
+    }
+    assert!(digests.iter().all(|d| d == &digests[0]), "{:?}", digests);
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[test]
+fn test_missing_input() {
+    let err = open_csv("no_such_dir/missing.csv", &ParseOptions::default()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "failed to open input file 'no_such_dir/missing.csv': not found"
+    );
+}
+
+#[tokio::test]
+async fn test_single_client_deterministic() -> Result<(), Error> {
+    let mut csv = "type,client,tx,amount\n".to_string();
+    for tx in 1..=500 {
+        csv.push_str(&format!("deposit,7,{},{}.{:04}\n", tx, tx % 13, tx));
+        if tx % 3 == 0 {
+            csv.push_str(&format!("dispute,7,{},\n", tx - 1));
+        }
+        if tx % 6 == 0 {
+            csv.push_str(&format!("resolve,7,{},\n", tx - 4));
+        }
+    }
+    let input = write_test_csv("deterministic.csv", &csv)?;
+
+    let expected = process_csv(input.clone(), ProcessOptions::default())
+        .await?
+        .to_string();
+    for _ in 0..100 {
+        let got = process_csv(input.clone(), ProcessOptions::default())
+            .await?
+            .to_string();
+        assert_eq!(got, expected);
+    }
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shard_count() -> Result<(), Error> {
+    // pass the value rather than set the variable, other tests running in
+    // parallel read it
+    assert_eq!(shard_count(Some("3"))?, 3);
+    assert!(shard_count(Some("0")).is_err());
+    assert!(shard_count(Some("x")).is_err());
+    assert_eq!(
+        shard_count(None)?,
+        min(num_cpus::get(), u16::MAX as usize) as u16
+    );
+
+    // an explicit count wins over the variable or cpu count
+    let input = write_test_csv(
+        "shard_count.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,1.0\n",
+    )?;
+    let opts = ProcessOptions {
+        shards: Some(3),
+        ..Default::default()
+    };
+    let ran = run_shards(&input, &opts, |i, _| Ok(i)).await;
+    std::fs::remove_file(input)?;
+    assert_eq!(ran?, vec![0, 1, 2]);
+    Ok(())
+}
+
+#[test]
+fn test_utf16_input() -> Result<(), Error> {
+    let mut contents = vec![0xFF, 0xFE];
+    for c in "type,client,tx,amount\ndeposit,1,1,1.0\n".encode_utf16() {
+        contents.extend(c.to_le_bytes());
+    }
+    let input = write_test_csv("utf16.csv", contents)?;
+    let err = open_csv(&input, &ParseOptions::default()).unwrap_err();
+    std::fs::remove_file(input)?;
+    assert_eq!(
+        err.to_string(),
+        "input appears to be UTF-16, expected UTF-8"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shard_hints_routing() -> Result<(), Error> {
+    let input = write_test_csv(
+        "hinted.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,5,2,1.0\ndeposit,2,3,1.0\n",
+    )?;
+    let hints = write_test_csv("hints.csv", "client,shard\n1,2\n5,3\n")?;
+    let opts = ProcessOptions {
+        shards: Some(4),
+        shard_hints: ShardHints::from_path(&hints)?,
+        ..Default::default()
+    };
+    let clients_per_shard = |_, shard: Clients| {
+        let mut ids: Vec<u16> = shard.balance_map.keys().map(|(c, _)| c.id()).collect();
+        ids.sort();
+        Ok(ids)
+    };
+    let routed = run_shards(&input, &opts, clients_per_shard).await?;
+    std::fs::remove_file(input)?;
+    std::fs::remove_file(hints)?;
+
+    // 1 and 5 would both be on shard 1 by modulo
+    assert_eq!(routed, vec![vec![], vec![], vec![1, 2], vec![5]]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_process_stream() -> Result<(), Error> {
+    use crate::ids::{ClientId, TxId};
+    use crate::transaction::TranType;
+    use rust_decimal_macros::dec;
+
+    let transactions = vec![
+        Transaction::new(TranType::Deposit, ClientId(2), TxId(1), Some(dec!(3.0))),
+        Transaction::new(TranType::Deposit, ClientId(1), TxId(2), Some(dec!(1.5))),
+        Transaction::new(TranType::Withdrawal, ClientId(2), TxId(3), Some(dec!(1.0))),
+        Transaction::new(TranType::Dispute, ClientId(1), TxId(2), None),
+    ];
+    let opts = ProcessOptions {
+        shards: Some(2),
+        ..Default::default()
+    };
+    let clients = process_stream(stream::iter(transactions.into_iter().map(Ok)), opts).await?;
+    assert_eq!(
+        clients.to_string(),
+        "1,0.0,1.5,1.5,false\n2,2.0,0,2.0,false\n"
+    );
+
+    // source errors and reused ids stop processing
+    let failing = vec![
+        Ok(Transaction::new(
+            TranType::Deposit,
+            ClientId(1),
+            TxId(1),
+            Some(dec!(1.0)),
+        )),
+        Err(anyhow!("source went away")),
+    ];
+    let err = process_stream(stream::iter(failing), ProcessOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "source went away");
+
+    let reused = vec![
+        Transaction::new(TranType::Deposit, ClientId(1), TxId(1), Some(dec!(1.0))),
+        Transaction::new(TranType::Deposit, ClientId(2), TxId(1), Some(dec!(1.0))),
+    ];
+    let err = process_stream(
+        stream::iter(reused.into_iter().map(Ok)),
+        ProcessOptions::default(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Reused transaction 1");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_leading_comments() -> Result<(), Error> {
+    let input = write_test_csv(
+        "comments.csv",
+        "# generated: 2024-01-01\n# source: feed\ntype,client,tx,amount\ndeposit,1,1,1.0\n",
+    )?;
+    let clients = process_csv(input.clone(), ProcessOptions::default()).await?;
+    assert_eq!(clients.to_string(), "1,1.0,0,1.0,false\n");
+
+    // only leading lines are skipped
+    std::fs::write(
+        &input,
+        "# generated: 2024-01-01\ntype,client,tx,amount\n#deposit,1,1,1.0\n",
+    )?;
+    assert!(process_csv(input.clone(), ProcessOptions::default())
+        .await
+        .is_err());
+
+    // and only with the prefix set
+    let opts = ProcessOptions {
+        parse: ParseOptions {
+            comment_prefix: String::new(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let err = process_csv(input.clone(), opts).await.unwrap_err();
+    assert_eq!(err.to_string(), "Invalid header # generated: 2024-01-01");
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keep_good_shards() -> Result<(), Error> {
+    use crate::clients::tran;
+    use crate::transaction::TranType;
+    use rust_decimal::Decimal;
+
+    // client 1 overflows, client 2 is on the other shard
+    let transactions = || {
+        stream::iter(
+            [(1, 1), (2, 2), (1, 3), (1, 4)]
+                .map(|(client, tx)| Ok(tran(TranType::Deposit, client, tx, Some(Decimal::MAX)))),
+        )
+    };
+    let opts = ProcessOptions {
+        shards: Some(2),
+        ..Default::default()
+    };
+    assert!(process_stream(transactions(), opts.clone()).await.is_err());
+
+    let opts = ProcessOptions {
+        keep_good_shards: true,
+        ..opts
+    };
+    let clients = process_stream(transactions(), opts).await?;
+    assert_eq!(
+        clients.to_string(),
+        format!("2,{},0,{},false\n", Decimal::MAX, Decimal::MAX)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_control_row() -> Result<(), Error> {
+    let opts = ProcessOptions {
+        parse: ParseOptions {
+            control_type: Some("control".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let input = write_test_csv(
+        "control.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\ncontrol,0,0,2\n",
+    )?;
+    let clients = process_csv(input.clone(), opts.clone()).await?;
+    assert_eq!(clients.to_string(), "1,1.0,0,1.0,false\n");
+
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,1.0\ncontrol,0,0,2\n",
+    )?;
+    let err = process_csv(input.clone(), opts).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "control row expects 2 transactions, read 1"
+    );
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_skip_locked_output() -> Result<(), Error> {
+    use crate::clients::tran;
+    use rust_decimal_macros::dec;
+
+    let mut csv =
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\ndispute,1,1,\nchargeback,1,1,\n"
+            .to_string();
+    for tx in 3..1000 {
+        csv.push_str(&format!("deposit,{},{},1.0\n", 1 + tx % 2, tx));
+    }
+    let input = write_test_csv("skip_locked.csv", &csv)?;
+    let expected = process_csv(input.clone(), ProcessOptions::default()).await?;
+    let opts = ProcessOptions {
+        skip_locked: true,
+        ..Default::default()
+    };
+    let skipped = process_csv(input.clone(), opts).await?;
+    std::fs::remove_file(input)?;
+    assert_eq!(skipped.to_string(), expected.to_string());
+    assert_eq!(
+        skipped.to_string(),
+        "1,0.0,0.0,0.0,true\n2,500.0,0,500.0,false\n"
+    );
+
+    // once the shard has seen the chargeback, client 1's later rows never
+    // reach it
+    let mut transactions = vec![
+        tran(TranType::Deposit, 1, 1, Some(dec!(5))),
+        tran(TranType::Deposit, 2, 2, Some(dec!(1))),
+        tran(TranType::Dispute, 1, 1, None),
+        tran(TranType::Chargeback, 1, 1, None),
+    ];
+    for tx in 3u16..13 {
+        transactions.push(tran(
+            TranType::Deposit,
+            1 + tx % 2,
+            tx.into(),
+            Some(dec!(1)),
+        ));
+    }
+    let opts = ProcessOptions {
+        skip_locked: true,
+        ..Default::default()
+    };
+    let rows = stream::iter(transactions.into_iter().enumerate()).then(|(i, t)| async move {
+        if i == 4 {
+            // let the shards catch up and report the lock
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        }
+        Ok(t)
+    });
+    let clients = process_stream(rows, opts.clone()).await?;
+    assert_eq!(opts.skipped_locked.load(Ordering::Relaxed), 5);
+    assert_eq!(clients.to_string(), "1,0,0,0,true\n2,6,0,6,false\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fail_on_any_lock() -> Result<(), Error> {
+    use crate::clients::AccountLocked;
+
+    let mut csv =
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,\nchargeback,1,1,\n".to_string();
+    for tx in 2..1000 {
+        csv.push_str(&format!("deposit,{},{},1.0\n", tx % 4, tx));
+    }
+    let input = write_test_csv("fail_on_lock.csv", &csv)?;
+    let opts = ProcessOptions {
+        shards: Some(2),
+        policy: Policy {
+            fail_on_lock: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let err = process_csv(input.clone(), opts).await.unwrap_err();
+    std::fs::remove_file(input)?;
+    assert!(err.is::<AccountLocked>(), "{:?}", err);
+    assert_eq!(err.to_string(), "Client 1 locked by chargeback of tx 1");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allow_clients() -> Result<(), Error> {
+    // client 2 reuses tx 1, which only matters if its rows are processed
+    let input = write_test_csv(
+        "allow_clients.csv",
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,1,1.0\ndeposit,3,2,2.0\n\
+         withdrawal,1,3,1.0\ndispute,2,1,\n",
+    )?;
+    for two_phase in [false, true] {
+        let opts = ProcessOptions {
+            two_phase,
+            allow_clients: Some(AllowClients::parse("1")?),
+            ..Default::default()
+        };
+        let clients = process_csv(input.clone(), opts).await?;
+        assert_eq!(clients.to_string(), "1,4.0,0,4.0,false\n");
+    }
+    let all = process_csv(input.clone(), ProcessOptions::default()).await;
+    std::fs::remove_file(input)?;
+    assert!(all.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allow_clients_opening() -> Result<(), Error> {
+    // client 2's opening balance is outside the allowlist like its rows
+    let opening = write_test_csv("allow_opening.csv", "client,available\n1,2.0\n2,3.0\n")?;
+    let input = write_test_csv(
+        "allow_clients_opening.csv",
+        "type,client,tx,amount\ndeposit,1,1,5.0\n",
+    )?;
+    let opts = ProcessOptions {
+        allow_clients: Some(AllowClients::parse("1")?),
+        opening: OpeningBalances::from_path(&opening)?,
+        ..Default::default()
+    };
+    let clients = process_csv(input.clone(), opts).await;
+    std::fs::remove_file(opening)?;
+    std::fs::remove_file(input)?;
+    assert_eq!(clients?.to_string(), "1,7.0,0,7.0,false\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch() -> Result<(), Error> {
+    // batch b reuses tx 1, which only matters if both batches are processed
+    let input = write_test_csv(
+        "batch.csv",
+        "type,client,tx,amount,batch\ndeposit,1,1,5.0,a\ndeposit,1,1,7.0,b\n\
+         deposit,2,2,1.0,b\nwithdrawal,1,3,2.0,b\ndeposit,3,4,1.0,\n",
+    )?;
+    let picked = |batch: &str| ProcessOptions {
+        parse: ParseOptions {
+            batch: Some(batch.to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let b = process_csv(input.clone(), picked("b")).await;
+    let a = process_csv(input.clone(), picked("a")).await;
+    let all = process_csv(input.clone(), ProcessOptions::default()).await;
+    std::fs::remove_file(input)?;
+    assert_eq!(b?.to_string(), "1,5.0,0,5.0,false\n2,1.0,0,1.0,false\n");
+    assert_eq!(a?.to_string(), "1,5.0,0,5.0,false\n");
+    assert!(all.is_err());
+
+    let input = write_test_csv("no_batch.csv", "type,client,tx,amount\ndeposit,1,1,5.0\n")?;
+    let err = process_csv(input.clone(), picked("a")).await.unwrap_err();
+    std::fs::remove_file(input)?;
+    assert_eq!(err.to_string(), "No batch column to pick a batch from");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_per_shard_output() -> Result<(), Error> {
+    use crate::ids::ClientId;
+
+    let mut csv = "type,client,tx,amount\n".to_string();
+    for client in 0..10 {
+        csv.push_str(&format!("deposit,{},{},1.0\n", client, client));
+    }
+    let input = write_test_csv("per_shard.csv", &csv)?;
+    let prefix = crate::testutil::test_path("per-shard");
+    let opts = ProcessOptions {
+        shards: Some(3),
+        per_shard_output: Some(prefix.clone()),
+        ..Default::default()
+    };
+    process_csv(input.clone(), opts).await?;
+    std::fs::remove_file(input)?;
+
+    for shard in 0..3u16 {
+        let path = format!("{}-shard-{}.csv", prefix, shard);
+        let written = std::fs::read_to_string(&path);
+        std::fs::remove_file(&path)?;
+        let expected: String = (0..10u16)
+            // routed the same way as the shards do
+            .filter(|client| ShardHints::default().shard_for(ClientId(*client), 3) == shard)
+            .map(|client| format!("{},1.0,0,1.0,false\n", client))
+            .collect();
+        assert_eq!(
+            written?,
+            format!("client,available,held,total,locked\n{}", expected)
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reorder_window() -> Result<(), Error> {
+    // the withdrawal is first in the file but took effect after the deposit
+    let input = write_test_csv(
+        "reorder.csv",
+        "type,client,tx,amount,timestamp\nwithdrawal,1,1,4.0,20\ndeposit,1,2,10.0,10\n\
+         deposit,2,3,1.0,30\nwithdrawal,2,4,1.0,30\n",
+    )?;
+    let in_file_order = process_csv(input.clone(), ProcessOptions::default()).await?;
+    let opts = ProcessOptions {
+        reorder_window: Some(16),
+        ..Default::default()
+    };
+    let reordered = process_csv(input.clone(), opts).await?;
+    std::fs::remove_file(input)?;
+    assert_eq!(
+        in_file_order.to_string(),
+        "1,10.0,0,10.0,false\n2,0.0,0,0,false\n"
+    );
+    // equal timestamps keep file order
+    assert_eq!(
+        reordered.to_string(),
+        "1,6.0,0,6.0,false\n2,0.0,0,0,false\n"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_input_bytes() -> Result<(), Error> {
+    let contents = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    let input = write_test_csv("max_input_bytes.csv", contents)?;
+    let over = ProcessOptions {
+        max_input_bytes: Some(contents.len() as u64 - 1),
+        ..Default::default()
+    };
+    let err = process_csv(input.clone(), over).await.unwrap_err();
+    let at_limit = ProcessOptions {
+        max_input_bytes: Some(contents.len() as u64),
+        ..Default::default()
+    };
+    let clients = process_csv(input.clone(), at_limit).await;
+    std::fs::remove_file(&input)?;
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "input file '{}' is {} bytes, over the {} byte limit",
+            input,
+            contents.len(),
+            contents.len() - 1
+        )
+    );
+    assert_eq!(clients?.to_string(), "1,1.0,0,1.0,false\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_expect_type() -> Result<(), Error> {
+    let input = write_test_csv(
+        "expect_type.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,0.5\ndeposit,2,3,2.0\n",
+    )?;
+    let opts = || ProcessOptions {
+        parse: ParseOptions {
+            expect_type: Some(TranType::Deposit),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let err = process_csv(input.clone(), opts()).await.unwrap_err();
+    let two_phase = ProcessOptions {
+        two_phase: true,
+        ..opts()
+    };
+    let validate_err = process_csv(input.clone(), two_phase).await.unwrap_err();
+    std::fs::remove_file(input)?;
+    assert_eq!(
+        err.to_string(),
+        "CSV deserialize error: record 2 (line: 3, byte: 38): \
+         withdrawal in a file expected to hold only deposit"
+    );
+    assert!(
+        format!("{:#}", validate_err)
+            .contains("withdrawal in a file expected to hold only deposit"),
+        "{:#}",
+        validate_err
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_process_reader() -> Result<(), Error> {
+    let contents = "# exported 2022-10-01
+type,client,tx,amount
+deposit,2,1,1.0
+deposit,1,2,2.0
+withdrawal,2,3,0.5
+";
+    let opts = ProcessOptions {
+        parse: ParseOptions {
+            comment_prefix: "#".to_string(),
+            ..Default::default()
+        },
+        shards: Some(3),
+        ..Default::default()
+    };
+    let clients = process_reader(Cursor::new(contents), opts.clone()).await?;
+    assert_eq!(
+        clients.to_string(),
+        "1,2.0,0,2.0,false\n2,0.5,0,0.5,false\n"
+    );
+
+    // same as reading it from a file
+    let input = write_test_csv("reader.csv", contents)?;
+    let from_file = process_csv(input.clone(), opts).await;
+    std::fs::remove_file(input)?;
+    assert_eq!(from_file?.to_string(), clients.to_string());
+
+    let err = process_reader(
+        Cursor::new("type,client,tx,value\n"),
+        ProcessOptions::default(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Invalid header value");
+    let err = process_reader(
+        Cursor::new("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,1,1.0\n"),
+        ProcessOptions::default(),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("Reused transaction 1"), "{}", err);
+
+    let opts = ProcessOptions {
+        two_phase: true,
+        ..Default::default()
+    };
+    assert!(process_reader(Cursor::new(contents), opts).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_assets() -> Result<(), Error> {
+    let input = write_test_csv(
+        "assets.csv",
+        "type,client,tx,asset,amount
+deposit,1,1,USD,10.0
+deposit,1,2,BTC,0.5
+deposit,2,3,USD,3.0
+deposit,3,4,,1.0
+dispute,1,2,BTC,
+withdrawal,1,5,USD,4.0
+",
+    )?;
+    let opts = ProcessOptions {
+        shards: Some(3),
+        ..Default::default()
+    };
+    let out_opts = OutputOptions::default();
+
+    let mut out = Vec::new();
+    process_csv(input.clone(), opts.clone())
+        .await?
+        .write_to(&mut out, &out_opts)?;
+    assert_eq!(
+        String::from_utf8(out.clone())?,
+        "client,asset,available,held,total,locked
+1,BTC,0.0,0.5,0.5,false
+1,USD,6.0,0,6.0,false
+2,USD,3.0,0,3.0,false
+3,,1.0,0,1.0,false
+"
+    );
+
+    // the shard without assets still lines up with those that have them
+    let mut low = Vec::new();
+    process_csv_low_memory(input.clone(), opts, &mut low, &out_opts).await?;
+    assert_eq!(String::from_utf8(low)?, String::from_utf8(out)?);
+
+    std::fs::remove_file(input)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_assert_sorted() -> Result<(), Error> {
+    let input = write_test_csv(
+        "assert_sorted.csv",
+        "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,3,1.0
+dispute,1,1,
+withdrawal,2,4,1.0
+deposit,2,2,1.0
+",
+    )?;
+    let opts = ProcessOptions {
+        parse: ParseOptions {
+            assert_sorted: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let err = process_csv(input.clone(), opts.clone()).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Transaction 2 is not after 4, input is not sorted by tx id"
+    );
+    let two_phase = ProcessOptions {
+        two_phase: true,
+        ..opts
+    };
+    let err = process_csv(input.clone(), two_phase).await.unwrap_err();
+    assert!(
+        err.to_string().contains("Transaction 2 is not after 4"),
+        "{}",
+        err
+    );
+
+    // without the flag the order doesn't matter
+    let clients = process_csv(input.clone(), ProcessOptions::default()).await?;
+    std::fs::remove_file(input)?;
+    assert_eq!(
+        clients.to_string(),
+        "1,0.0,5.0,5.0,false\n2,1.0,0,1.0,false\n"
+    );
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, bail, Context, Error};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::balance::Balance;
+use crate::clients::Clients;
+use crate::ids::{AssetId, ClientId};
+
+/// One row of a rates file
+#[derive(Debug, Deserialize)]
+struct RateRow {
+    currency: AssetId,
+    rate: Decimal,
+}
+
+/// Exchange rates into a reporting currency, how many units of it one unit
+/// of each currency is worth
+#[derive(Clone, Debug, Default)]
+pub struct Rates {
+    rates: HashMap<AssetId, Decimal>,
+}
+
+impl Rates {
+    /// Read `currency,rate` rows, a currency listed twice or a rate that
+    /// isn't positive is an error
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("Opening rates {}", path))?;
+        let mut rates = HashMap::new();
+        for result in rdr.deserialize() {
+            let row: RateRow = result.with_context(|| format!("Reading rates {}", path))?;
+            if row.rate <= Decimal::ZERO {
+                bail!("Rate {} for {} is not positive", row.rate, row.currency.0);
+            }
+            if rates.insert(row.currency.clone(), row.rate).is_some() {
+                bail!("Currency {} has more than one rate", row.currency.0);
+            }
+        }
+        Ok(Self { rates })
+    }
+
+    /// The rate for a currency, the reporting currency itself being 1
+    fn rate(
+        &self,
+        client: ClientId,
+        currency: &AssetId,
+        report: &AssetId,
+    ) -> Result<Decimal, Error> {
+        if currency.is_implicit() {
+            bail!(
+                "Client {} has a balance with no asset, which can't be converted",
+                client.id()
+            );
+        }
+        match self.rates.get(currency) {
+            Some(rate) => Ok(*rate),
+            None if currency == report => Ok(Decimal::ONE),
+            None => bail!(
+                "No rate for {} to convert client {}",
+                currency.0,
+                client.id()
+            ),
+        }
+    }
+
+    /// Each client's balances converted to `report` and summed into one
+    /// balance in it, rounded to `dp` places, e.g. those allowed for `report`
+    /// in the input. It is locked if any of the client's assets is. Only the
+    /// amounts carry over, not the transaction records.
+    pub fn consolidate(
+        &self,
+        clients: &Clients,
+        report: &AssetId,
+        dp: u32,
+    ) -> Result<Clients, Error> {
+        let mut totals: BTreeMap<ClientId, (Decimal, Decimal, bool)> = BTreeMap::new();
+        for ((client, asset), balance) in clients.iter_sorted() {
+            let rate = self.rate(*client, asset, report)?;
+            let overflow = || anyhow!("Converting client {} overflowed", client.id());
+            let total = totals.entry(*client).or_default();
+            let available = balance.available().checked_mul(rate).ok_or_else(overflow)?;
+            let held = balance.held().checked_mul(rate).ok_or_else(overflow)?;
+            total.0 = total.0.checked_add(available).ok_or_else(overflow)?;
+            total.1 = total.1.checked_add(held).ok_or_else(overflow)?;
+            total.2 |= balance.locked();
+        }
+
+        let mut consolidated = Clients::with_capacity(clients.policy.clone(), totals.len());
+        for (client, (available, held, locked)) in totals {
+            consolidated.balance_map.insert(
+                (client, report.clone()),
+                Balance::from_parts(
+                    round_to(available, dp),
+                    round_to(held, dp),
+                    locked,
+                    HashMap::new(),
+                ),
+            );
+        }
+        Ok(consolidated)
+    }
+}
+
+/// Round to at most `dp` places, leaving an amount with fewer as it is, where
+/// `round_dp` would pad a zero out to `dp` places
+fn round_to(d: Decimal, dp: u32) -> Decimal {
+    if d.scale() > dp {
+        d.round_dp(dp)
+    } else {
+        d
+    }
+}
+
+#[test]
+fn test_consolidate() -> Result<(), Error> {
+    use crate::clients::{process_all, tran};
+    use crate::testutil::write_test_csv;
+    use crate::transaction::{TranType, MAX_DP};
+    use rust_decimal_macros::dec;
+
+    let path = write_test_csv("rates.csv", "currency,rate\nUSD,1\nEUR, 1.1\n")?;
+    let rates = Rates::from_path(&path);
+    std::fs::remove_file(&path)?;
+    let rates = rates?;
+
+    let asset = |name: &str| Some(AssetId(name.to_string()));
+    let mut clients = Clients::default();
+    process_all(
+        &mut clients,
+        &[
+            tran(TranType::Deposit, 1, 1, Some(dec!(10))).with_asset(asset("USD")),
+            tran(TranType::Deposit, 1, 2, Some(dec!(20))).with_asset(asset("EUR")),
+            tran(TranType::Dispute, 1, 2, None).with_asset(asset("EUR")),
+            tran(TranType::Deposit, 2, 3, Some(dec!(5))).with_asset(asset("EUR")),
+            tran(TranType::Dispute, 2, 3, None).with_asset(asset("EUR")),
+            tran(TranType::Chargeback, 2, 3, None).with_asset(asset("EUR")),
+            tran(TranType::Deposit, 2, 4, Some(dec!(1.5))).with_asset(asset("USD")),
+        ],
+    )?;
+    let consolidated = rates.consolidate(&clients, &AssetId("USD".to_string()), MAX_DP)?;
+    assert_eq!(
+        consolidated.to_string(),
+        "1,USD,10,22.0,32.0,false\n2,USD,1.5,0,1.5,true\n"
+    );
+
+    // a rate with many places is rounded to the report currency's
+    let path = write_test_csv("rates_dp.csv", "currency,rate\nEUR,1.123456789\n")?;
+    let precise = Rates::from_path(&path);
+    std::fs::remove_file(&path)?;
+    let precise = precise?;
+    let consolidated = precise.consolidate(&clients, &AssetId("USD".to_string()), MAX_DP)?;
+    assert_eq!(
+        consolidated.to_string(),
+        "1,USD,10,22.4691,32.4691,false\n2,USD,1.5,0,1.5,true\n"
+    );
+    let consolidated = precise.consolidate(&clients, &AssetId("USD".to_string()), 0)?;
+    assert_eq!(
+        consolidated.to_string(),
+        "1,USD,10,22,32,false\n2,USD,2,0,2,true\n"
+    );
+
+    // a currency with no rate can't be converted
+    process_all(
+        &mut clients,
+        &[tran(TranType::Deposit, 3, 5, Some(dec!(1))).with_asset(asset("BTC"))],
+    )?;
+    let err = rates
+        .consolidate(&clients, &AssetId("USD".to_string()), MAX_DP)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "No rate for BTC to convert client 3");
+
+    std::fs::write(&path, "currency,rate\nUSD,0\n")?;
+    let err = Rates::from_path(&path).unwrap_err();
+    std::fs::remove_file(&path)?;
+    assert_eq!(err.to_string(), "Rate 0 for USD is not positive");
+    Ok(())
+}
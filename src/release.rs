@@ -0,0 +1,101 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::ids::{AssetId, ClientId, TxId};
+
+/// One client's disputes waiting to be released, oldest first
+#[derive(Debug, Default)]
+struct ClientQueue {
+    /// Transactions seen for the client
+    seen: u32,
+    /// The client's transaction count when each dispute was raised
+    queue: VecDeque<(u32, AssetId, TxId)>,
+    /// When the open dispute on each transaction was raised, so an entry
+    /// left over from an earlier dispute of it doesn't release a later one
+    raised_at: HashMap<(AssetId, TxId), u32>,
+}
+
+/// Schedules disputes to be released back to available once `after` more of
+/// the client's transactions have been applied without a chargeback.
+///
+/// Ages count per client, as `Clients::open_dispute_report` does, so which
+/// shard a client lands on doesn't change when its disputes release. Disputes
+/// are queued in the order raised, so each transaction only looks at the
+/// front of its client's queue and the cost stays proportional to what is
+/// released.
+#[derive(Debug)]
+pub struct AutoRelease {
+    after: u32,
+    clients: HashMap<ClientId, ClientQueue>,
+}
+
+impl AutoRelease {
+    pub fn new(after: u32) -> Self {
+        Self {
+            after,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Count a transaction for the client
+    pub fn tick(&mut self, client: ClientId) {
+        let queue = self.clients.entry(client).or_default();
+        queue.seen = queue.seen.saturating_add(1);
+    }
+
+    /// Note a dispute raised by the client's latest transaction
+    pub fn raised(&mut self, client: ClientId, asset: AssetId, tx: TxId) {
+        let queue = self.clients.entry(client).or_default();
+        queue.queue.push_back((queue.seen, asset.clone(), tx));
+        queue.raised_at.insert((asset, tx), queue.seen);
+    }
+
+    /// Disputes raised `after` or more of the client's transactions ago, to
+    /// be resolved if still open
+    pub fn due(&mut self, client: ClientId) -> Vec<(AssetId, TxId)> {
+        let mut due = Vec::new();
+        let queue = match self.clients.get_mut(&client) {
+            Some(queue) => queue,
+            None => return due,
+        };
+        while let Some((at, _, _)) = queue.queue.front() {
+            if queue.seen - at < self.after {
+                break;
+            }
+            if let Some((at, asset, tx)) = queue.queue.pop_front() {
+                let key = (asset, tx);
+                if queue.raised_at.get(&key) == Some(&at) {
+                    queue.raised_at.remove(&key);
+                    due.push(key);
+                }
+            }
+        }
+        due
+    }
+}
+
+#[test]
+fn test_auto_release() {
+    let client = ClientId(1);
+    let asset = AssetId::default();
+    let mut release = AutoRelease::new(2);
+    release.tick(client);
+    release.raised(client, asset.clone(), TxId(1));
+    release.tick(client);
+    assert!(release.due(client).is_empty());
+    release.tick(client);
+    assert_eq!(release.due(client), vec![(asset.clone(), TxId(1))]);
+
+    // resolved and disputed again, the first dispute's entry doesn't
+    // release the second early
+    release.tick(client);
+    release.raised(client, asset.clone(), TxId(2));
+    release.tick(client);
+    release.tick(client);
+    release.raised(client, asset.clone(), TxId(2));
+    assert!(release.due(client).is_empty());
+    assert!(release.due(ClientId(2)).is_empty());
+    release.tick(client);
+    assert!(release.due(client).is_empty());
+    release.tick(client);
+    assert_eq!(release.due(client), vec![(asset, TxId(2))]);
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Error};
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use crate::transaction::Transaction;
+
+/// A buffered transaction, ordered by timestamp and then arrival
+#[derive(Debug)]
+struct Pending {
+    key: (u64, u64),
+    t: Transaction,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Reorders one shard's transactions by their timestamp column.
+///
+/// Holds at most `window` transactions, releasing the earliest once full, so
+/// a transaction can only move ahead of ones that arrived fewer than `window`
+/// rows before it. Equal timestamps keep their file order.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    window: usize,
+    arrived: u64,
+    heap: BinaryHeap<Reverse<Pending>>,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            arrived: 0,
+            heap: BinaryHeap::with_capacity(window + 1),
+        }
+    }
+
+    /// Buffer a transaction, returning the earliest one if the window is full
+    pub fn push(&mut self, t: Transaction) -> Result<Option<Transaction>, Error> {
+        let timestamp = t
+            .timestamp
+            .ok_or_else(|| anyhow!("tx {} has no timestamp to order by", t.tx.id()))?;
+        self.heap.push(Reverse(Pending {
+            key: (timestamp, self.arrived),
+            t,
+        }));
+        self.arrived += 1;
+        if self.heap.len() > self.window {
+            return Ok(self.heap.pop().map(|Reverse(pending)| pending.t));
+        }
+        Ok(None)
+    }
+
+    /// Everything still buffered, in order, once the input is done
+    pub fn drain(self) -> impl Iterator<Item = Transaction> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .rev()
+            .map(|Reverse(pending)| pending.t)
+    }
+}
+
+#[test]
+fn test_reorder_buffer() -> Result<(), Error> {
+    use crate::ids::{ClientId, TxId};
+    use crate::transaction::TranType;
+
+    let at = |tx, timestamp| {
+        Transaction::new(TranType::Dispute, ClientId(1), TxId(tx), None).with_timestamp(timestamp)
+    };
+    let mut reorder = ReorderBuffer::new(3);
+    let mut released = Vec::new();
+    for t in [
+        at(1, Some(5)),
+        at(2, Some(3)),
+        at(3, Some(5)),
+        at(4, Some(1)),
+    ] {
+        released.extend(reorder.push(t)?);
+    }
+    released.extend(reorder.drain());
+    let order: Vec<u32> = released.iter().map(|t| t.tx.id()).collect();
+    // tie between 1 and 3 keeps file order
+    assert_eq!(order, vec![4, 2, 1, 3]);
+
+    // the window bounds how far a row can move
+    let mut reorder = ReorderBuffer::new(1);
+    assert_eq!(reorder.push(at(1, Some(5)))?, None);
+    assert_eq!(reorder.push(at(2, Some(9)))?.map(|t| t.tx), Some(TxId(1)));
+    assert_eq!(reorder.push(at(3, Some(1)))?.map(|t| t.tx), Some(TxId(3)));
+
+    assert!(reorder.push(at(4, None)).is_err());
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use crate::dedup::TxScope;
+use crate::transaction::{ParseOptions, TranType, VALID_HEADERS};
+
+/// Describe the expected input format, generated from the validation settings
+pub fn schema_text(opts: &ParseOptions) -> String {
+    let mut types: Vec<TranType> = TranType::ALL.to_vec();
+    if opts.allow_admin {
+        types.extend(TranType::ADMIN);
+    }
+    let names: Vec<&str> = types.iter().map(|t| t.name()).collect();
+    let mut lines = Vec::new();
+    lines.push(format!(
+        "header: {} (any order, whitespace trimmed)",
+        VALID_HEADERS[..4].join(",")
+    ));
+    lines.push(format!(
+        "optional columns: {}",
+        VALID_HEADERS[4..].join(",")
+    ));
+    if !opts.comment_prefix.is_empty() {
+        lines.push(format!(
+            "leading lines starting with {} before the header are skipped",
+            opts.comment_prefix
+        ));
+    }
+    lines.push("columns:".to_string());
+    lines.push(format!(
+        "  type: {}{}",
+        match opts.expect_type {
+            Some(expected) => format!("{} only", expected.name()),
+            None => format!("one of {}", names.join(", ")),
+        },
+        if opts.normalize_type_whitespace {
+            ", whitespace inside it ignored"
+        } else {
+            ""
+        }
+    ));
+    lines.push(format!("  client: u16, 0 to {}", u16::MAX));
+    lines.push(format!(
+        "  tx: u32, 0 to {}, unique across {}deposits and withdrawals{}",
+        u32::MAX,
+        match opts.tx_scope {
+            TxScope::Global => "all ",
+            TxScope::PerClient => "each client's ",
+        },
+        if opts.assert_sorted {
+            ", and increasing from one to the next"
+        } else {
+            ""
+        }
+    ));
+    lines.push(format!(
+        "  amount: positive decimal, at most {} dp{}, no leading decimal point{}",
+        opts.max_dp,
+        if opts.trim_trailing_zeros {
+            " not counting trailing zeros"
+        } else {
+            ""
+        },
+        if opts.allow_grouping {
+            ", quoted comma thousands separators allowed"
+        } else {
+            ""
+        }
+    ));
+    let mut asset_dp: Vec<_> = opts.asset_dp.iter().collect();
+    asset_dp.sort();
+    for (asset, dp) in asset_dp {
+        lines.push(format!("    at most {} dp for asset {}", dp, asset.0));
+    }
+    lines.push("  reason: free text, chargeback only".to_string());
+    lines.push(
+        "  asset: asset id, balances are kept per client and asset, selects the amount precision"
+            .to_string(),
+    );
+    lines.push(match &opts.batch {
+        Some(batch) => format!(
+            "  batch: required, only rows of batch {} are processed",
+            batch
+        ),
+        None => "  batch: batch id, not used unless a batch is picked".to_string(),
+    });
+    lines.push("  timestamp: u64, only used to reorder rows within a window".to_string());
+    lines.push("rules:".to_string());
+    for t in types {
+        let rule = match t {
+            TranType::Deposit => "amount required, credits available",
+            TranType::Withdrawal => {
+                "amount required, debits available, ignored if insufficient funds"
+            }
+            TranType::Dispute => {
+                "no amount, moves a deposit or withdrawal by tx to held, unknown tx ignored"
+            }
+            TranType::Resolve => "no amount, releases a disputed tx back to available",
+            TranType::Chargeback => "no amount, reverses a disputed tx and locks the account",
+            TranType::Mint => "amount required, credits available, can't be disputed",
+            TranType::Burn => "amount required, debits available, errors if insufficient funds",
+        };
+        lines.push(format!("  {}: {}", t.name(), rule));
+    }
+    lines.push("  locked accounts ignore all further transactions for that client".to_string());
+    if let Some(name) = &opts.control_type {
+        lines.push(format!(
+            "  {}: required last row, amount is the count of rows before it, not a transaction",
+            name
+        ));
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+#[test]
+fn test_schema_text() {
+    use crate::ids::AssetId;
+    use std::collections::HashMap;
+
+    let schema = schema_text(&ParseOptions::default());
+    for t in ["deposit", "withdrawal", "dispute", "resolve", "chargeback"] {
+        assert!(schema.contains(&format!("  {}: ", t)), "{}", schema);
+    }
+    assert!(schema.contains("header: type,client,tx,amount"));
+    assert!(schema.contains("at most 4 dp"));
+    assert!(schema.contains("leading lines starting with # before the header are skipped"));
+
+    let opts = ParseOptions {
+        max_dp: 2,
+        asset_dp: HashMap::from([(AssetId("JPY".to_string()), 0)]),
+        ..Default::default()
+    };
+    let schema = schema_text(&opts);
+    assert!(schema.contains("at most 2 dp"));
+    assert!(schema.contains("at most 0 dp for asset JPY"));
+    assert!(!schema.contains("mint"));
+
+    let opts = ParseOptions {
+        allow_admin: true,
+        ..Default::default()
+    };
+    let schema = schema_text(&opts);
+    assert!(schema.contains("  mint: "));
+    assert!(schema.contains("  burn: "));
+}
@@ -0,0 +1,18 @@
+//! Fixtures shared by the tests of several modules
+
+use anyhow::Error;
+
+/// Path for a test file called `name` in the temp dir, unique to this process
+pub(crate) fn test_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("paytoy-{}-{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Write a test input file called `name`, returning its path
+pub(crate) fn write_test_csv(name: &str, contents: impl AsRef<[u8]>) -> Result<String, Error> {
+    let path = test_path(name);
+    std::fs::write(&path, contents)?;
+    Ok(path)
+}
@@ -1,11 +1,27 @@
 use anyhow::{bail, Error};
+use csv::StringRecord;
 use rust_decimal::Decimal;
 use serde::Deserialize;
 use serde::Deserializer;
 
-use crate::ids::{ClientId, TxId};
+use std::collections::HashMap;
 
-const MAX_DP: u32 = 4;
+use crate::dedup::TxScope;
+use crate::ids::{AccountId, AssetId, ClientId, TxId};
+
+pub const MAX_DP: u32 = 4;
+
+/// Columns the input may have, those after the first four being optional
+pub const VALID_HEADERS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "reason",
+    "asset",
+    "batch",
+    "timestamp",
+];
 
 /// types of transaction we can process
 #[derive(Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
@@ -16,6 +32,54 @@ pub enum TranType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Admin only, credits available without creating a disputable record
+    Mint,
+    /// Admin only, debits available without creating a disputable record
+    Burn,
+}
+
+impl TranType {
+    pub const ALL: [TranType; 5] = [
+        TranType::Deposit,
+        TranType::Withdrawal,
+        TranType::Dispute,
+        TranType::Resolve,
+        TranType::Chargeback,
+    ];
+
+    /// Admin types, only accepted with `ParseOptions::allow_admin`
+    pub const ADMIN: [TranType; 2] = [TranType::Mint, TranType::Burn];
+
+    /// The name used in the input type column
+    pub fn name(&self) -> &'static str {
+        match self {
+            TranType::Deposit => "deposit",
+            TranType::Withdrawal => "withdrawal",
+            TranType::Dispute => "dispute",
+            TranType::Resolve => "resolve",
+            TranType::Chargeback => "chargeback",
+            TranType::Mint => "mint",
+            TranType::Burn => "burn",
+        }
+    }
+
+    /// The type with this input name, admin types included
+    pub fn from_name(name: &str) -> Option<TranType> {
+        TranType::ALL
+            .iter()
+            .chain(TranType::ADMIN.iter())
+            .find(|t| t.name() == name)
+            .copied()
+    }
+
+    /// Dispute, resolve and chargeback refer back to an earlier transaction
+    /// rather than moving funds themselves
+    pub fn is_dispute_flow(&self) -> bool {
+        matches!(
+            self,
+            TranType::Dispute | TranType::Resolve | TranType::Chargeback
+        )
+    }
 }
 
 /// The input transaction
@@ -25,21 +89,156 @@ pub struct Transaction {
     pub client: ClientId,
     pub tx: TxId,
     pub amount: Option<Decimal>,
+    /// Why a chargeback happened, only allowed on chargebacks
+    pub reason: Option<String>,
+    /// Which asset the amount is in, if the input has an asset column
+    pub asset: Option<AssetId>,
+    /// Which batch the row came in, only used to pick rows with `--batch`
+    pub batch: Option<String>,
+    /// When the transaction took effect, only used to reorder with `--reorder-window`
+    pub timestamp: Option<u64>,
 }
 
 impl Transaction {
+    /// The client and asset whose balance this applies to
+    pub fn account(&self) -> AccountId {
+        (self.client, self.asset.clone().unwrap_or_default())
+    }
+
     pub fn new(tran_type: TranType, client: ClientId, tx: TxId, amount: Option<Decimal>) -> Self {
         Self {
             client,
             tx,
             tran_type,
             amount,
+            reason: None,
+            asset: None,
+            batch: None,
+            timestamp: None,
         }
     }
+
+    pub fn with_reason(self, reason: Option<String>) -> Self {
+        Self { reason, ..self }
+    }
+
+    pub fn with_asset(self, asset: Option<AssetId>) -> Self {
+        Self { asset, ..self }
+    }
+
+    pub fn with_batch(self, batch: Option<String>) -> Self {
+        Self { batch, ..self }
+    }
+
+    pub fn with_timestamp(self, timestamp: Option<u64>) -> Self {
+        Self { timestamp, ..self }
+    }
+}
+
+/// Options controlling how input rows are parsed and validated
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// Accept comma thousands separators in (quoted) amounts, e.g. "1,234.56"
+    pub allow_grouping: bool,
+    /// Decimal places allowed in amounts, unless overridden for the asset
+    pub max_dp: u32,
+    /// Per asset decimal places, e.g. JPY=0 or BTC=8
+    pub asset_dp: HashMap<AssetId, u32>,
+    /// Accept the mint and burn admin types, for building test fixtures
+    pub allow_admin: bool,
+    /// Leading lines starting with this are skipped before the header, empty disables
+    pub comment_prefix: String,
+    /// Type of a trailing control row carrying the row count, if the feed has one
+    pub control_type: Option<String>,
+    /// Drop trailing fractional zeros before the decimal places check, so
+    /// `1.23000` is accepted as `1.23`
+    pub trim_trailing_zeros: bool,
+    /// Only rows with this in the batch column are processed, others are dropped
+    pub batch: Option<String>,
+    /// Remove whitespace inside the type before matching it, e.g. `dep osit`
+    pub normalize_type_whitespace: bool,
+    /// Error if deposit and withdrawal tx ids don't strictly increase
+    pub assert_sorted: bool,
+    /// Where deposit and withdrawal tx ids have to be unique
+    pub tx_scope: TxScope,
+    /// Every row has to be this type, for files meant to hold only one
+    pub expect_type: Option<TranType>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            allow_grouping: false,
+            max_dp: MAX_DP,
+            asset_dp: HashMap::new(),
+            allow_admin: false,
+            comment_prefix: "#".to_string(),
+            control_type: None,
+            trim_trailing_zeros: false,
+            batch: None,
+            normalize_type_whitespace: false,
+            assert_sorted: false,
+            tx_scope: TxScope::Global,
+            expect_type: None,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// Whether the transaction is in the batch being processed, if one was picked
+    pub fn in_batch(&self, t: &Transaction) -> bool {
+        match &self.batch {
+            Some(batch) => t.batch.as_ref() == Some(batch),
+            None => true,
+        }
+    }
+
+    /// Decimal places allowed for amounts in the given asset
+    pub fn max_dp_for(&self, asset: Option<&AssetId>) -> u32 {
+        asset
+            .and_then(|asset| self.asset_dp.get(asset))
+            .copied()
+            .unwrap_or(self.max_dp)
+    }
+
+    /// The most decimal places any amount may have, across all assets
+    pub fn finest_dp(&self) -> u32 {
+        self.asset_dp.values().copied().fold(self.max_dp, u32::max)
+    }
+}
+
+/// Remove thousands separators, checking they are in sensible places
+fn strip_grouping(s: &str) -> Result<String, Error> {
+    let (int_part, frac_part) = match s.split_once('.') {
+        Some((i, f)) => (i, Some(f)),
+        None => (s, None),
+    };
+    if let Some(f) = frac_part {
+        if f.contains(',') {
+            bail!("grouping separator in fractional part: {}", s);
+        }
+    }
+    let mut groups = int_part.split(',');
+    let first = groups.next().unwrap_or_default();
+    if first.is_empty() || (first.len() > 3 && int_part.contains(',')) {
+        bail!("invalid grouping: {}", s);
+    }
+    let mut stripped = first.to_string();
+    for g in groups {
+        if g.len() != 3 {
+            bail!("invalid grouping: {}", s);
+        }
+        stripped.push_str(g);
+    }
+    if let Some(f) = frac_part {
+        stripped.push('.');
+        stripped.push_str(f);
+    }
+    Ok(stripped)
 }
 
 /// Respect the decimal point limit
-fn try_from_str(s: &str) -> Result<Option<Decimal>, Error> {
+fn try_from_str(s: &str, opts: &ParseOptions, max_dp: u32) -> Result<Option<Decimal>, Error> {
     let s = s.trim();
     Ok(if s.is_empty() {
         None
@@ -47,100 +246,199 @@ fn try_from_str(s: &str) -> Result<Option<Decimal>, Error> {
         if s.starts_with('.') {
             bail!("leading decimal point not allowed: {}", s);
         }
-        let d = Decimal::from_str_exact(s)?;
+        let d = if opts.allow_grouping && s.contains(',') {
+            Decimal::from_str_exact(&strip_grouping(s)?)?
+        } else {
+            Decimal::from_str_exact(s)?
+        };
+        // only removes zeros, so never changes the value
+        let d = if opts.trim_trailing_zeros {
+            d.normalize()
+        } else {
+            d
+        };
         if d.is_sign_negative() {
             bail!("negative amount: {}", s);
         } else if d == Decimal::ZERO {
             bail!("zero amount: {}", s);
-        } else if d.fract().scale() > MAX_DP {
+        } else if d.fract().scale() > max_dp {
             bail!("too many decimal places: {}", s);
         }
         Some(d)
     })
 }
 
-/// Custom deserializer to enforce max decimal places
-fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let v: Option<String> = Option::deserialize(deserializer)?;
-    if let Some(v) = v.as_ref() {
-        Ok(try_from_str(v).map_err(serde::de::Error::custom)?)
-    } else {
-        Ok(None)
+/// The input row as read, before amount parsing and validation
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawTransaction {
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub tran_type: TranType,
+    pub amount: Option<String>,
+    pub reason: Option<String>,
+    /// Which asset the amount is in, if the input has an asset column
+    pub asset: Option<AssetId>,
+    /// Which batch the row came in, if the input has a batch column
+    pub batch: Option<String>,
+    /// When the transaction took effect, if the input has a timestamp column
+    pub timestamp: Option<u64>,
+}
+
+impl RawTransaction {
+    /// Deserialize a row. An unknown type gets a clear error rather than
+    /// serde's list of variants, and with `normalize_type_whitespace` any
+    /// whitespace inside the type is removed first, e.g. `dep osit`.
+    pub fn from_record(
+        record: &StringRecord,
+        headers: &StringRecord,
+        opts: &ParseOptions,
+    ) -> Result<Self, Error> {
+        let type_col = headers.iter().position(|h| h == "type");
+        let mut normalized = None;
+        if let (true, Some(col)) = (opts.normalize_type_whitespace, type_col) {
+            let tran_type = record.get(col).unwrap_or_default();
+            if tran_type.contains(char::is_whitespace) {
+                let joined: String = tran_type.split_whitespace().collect();
+                let mut fields: StringRecord = record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| if i == col { joined.as_str() } else { field })
+                    .collect();
+                fields.set_position(record.position().cloned());
+                normalized = Some(fields);
+            }
+        }
+        let record = normalized.as_ref().unwrap_or(record);
+        let tran_type = type_col.and_then(|i| record.get(i));
+        record
+            .deserialize(Some(headers))
+            .map_err(|e| match tran_type {
+                Some(name) if TranType::from_name(name).is_none() => {
+                    Error::from(e).context(format!("Unknown transaction type '{}'", name))
+                }
+                _ => e.into(),
+            })
     }
 }
 
-/// Custom deserializer to enforce invariants on inputs
-impl<'de> Deserialize<'de> for Transaction {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        #[serde(deny_unknown_fields)]
-        struct Inner {
-            pub client: ClientId,
-            pub tx: TxId,
-            #[serde(rename = "type")]
-            pub tran_type: TranType,
-            #[serde(deserialize_with = "deserialize_amount")]
-            pub amount: Option<Decimal>,
+impl Transaction {
+    /// Enforce invariants on inputs
+    pub fn from_raw(raw: RawTransaction, opts: &ParseOptions) -> Result<Self, Error> {
+        // an empty amount is the same as none
+        if raw.tran_type.is_dispute_flow()
+            && matches!(raw.amount.as_deref().map(str::trim), Some(a) if !a.is_empty())
+        {
+            bail!("amount not allowed for {}", raw.tran_type.name());
         }
 
-        // Deserialize the inner struct
-        let inner = Inner::deserialize(deserializer)?;
+        // The asset decides the precision, so look at it before the amount
+        let max_dp = opts.max_dp_for(raw.asset.as_ref());
+        let amount = match raw.amount.as_deref() {
+            Some(v) => try_from_str(v, opts, max_dp)?,
+            None => None,
+        };
+
+        if TranType::ADMIN.contains(&raw.tran_type) && !opts.allow_admin {
+            bail!(
+                "{} is an admin transaction, not allowed",
+                raw.tran_type.name()
+            );
+        }
 
-        // Do the additional validation, if it fails return an error
-        let amount = match (inner.tran_type, inner.amount) {
-            (TranType::Deposit | TranType::Withdrawal, None) => Err(serde::de::Error::custom(
-                "amount required for deposit and withdrawal",
-            )),
-            (TranType::Dispute | TranType::Resolve | TranType::Chargeback, Some(_)) => Err(
-                serde::de::Error::custom("amount not allowed for dispute, resolve, or chargeback"),
+        match opts.expect_type {
+            Some(expected) if raw.tran_type != expected => bail!(
+                "{} in a file expected to hold only {}",
+                raw.tran_type.name(),
+                expected.name()
             ),
-            (TranType::Deposit | TranType::Withdrawal, Some(amount)) => Ok(Some(amount)),
-            (TranType::Dispute | TranType::Resolve | TranType::Chargeback, None) => Ok(None),
-        }?;
+            _ => {}
+        }
+
+        // Do the additional validation, if it fails return an error
+        let amount = match (raw.tran_type, amount) {
+            (TranType::Deposit | TranType::Withdrawal | TranType::Mint | TranType::Burn, None) => {
+                bail!("amount required for deposit, withdrawal, mint and burn")
+            }
+            // normally rejected above, but error rather than panic if that
+            // check and the amount parsing ever disagree
+            (TranType::Dispute | TranType::Resolve | TranType::Chargeback, Some(_)) => {
+                bail!("amount not allowed for {}", raw.tran_type.name())
+            }
+            (
+                TranType::Deposit | TranType::Withdrawal | TranType::Mint | TranType::Burn,
+                Some(amount),
+            ) => Some(amount),
+            (TranType::Dispute | TranType::Resolve | TranType::Chargeback, None) => None,
+        };
+
+        if raw.reason.is_some() && raw.tran_type != TranType::Chargeback {
+            bail!("reason only allowed for chargeback");
+        }
 
         // Return the actual contract
-        Ok(Transaction::new(
-            inner.tran_type,
-            inner.client,
-            inner.tx,
-            amount,
-        ))
+        Ok(Transaction::new(raw.tran_type, raw.client, raw.tx, amount)
+            .with_reason(raw.reason)
+            .with_asset(raw.asset)
+            .with_batch(raw.batch)
+            .with_timestamp(raw.timestamp))
+    }
+}
+
+/// Validate one input row against its header with the run's parse options,
+/// e.g. for editors or linters checking a line without a file
+pub fn validate_record(
+    headers: &StringRecord,
+    record: &StringRecord,
+    opts: &ParseOptions,
+) -> Result<Transaction, Error> {
+    let raw = RawTransaction::from_record(record, headers, opts)?;
+    Transaction::from_raw(raw, opts)
+}
+
+/// Custom deserializer to enforce invariants on inputs, using default parse options
+impl<'de> Deserialize<'de> for Transaction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawTransaction::deserialize(deserializer)?;
+        Transaction::from_raw(raw, &ParseOptions::default()).map_err(serde::de::Error::custom)
     }
 }
 
+#[cfg(test)]
+fn try_from_str_default(s: &str) -> Result<Option<Decimal>, Error> {
+    try_from_str(s, &ParseOptions::default(), MAX_DP)
+}
+
 #[test]
 fn test_from_str() -> Result<(), Error> {
     use rust_decimal_macros::dec;
 
-    assert_eq!(try_from_str("")?, None);
-    assert_eq!(try_from_str("1.1")?, Some(dec!(1.1)));
-    assert_eq!(try_from_str(" 1.1 ")?, Some(dec!(1.1)));
-
-    assert!(try_from_str("0.0").is_err());
-    assert!(try_from_str("0").is_err());
-    assert!(try_from_str("0.23456").is_err());
-    assert!(try_from_str("0.234.56").is_err());
-    assert!(try_from_str("0.2345.6").is_err());
-    assert!(try_from_str(".2345").is_err());
-    assert!(try_from_str("10.23456").is_err());
-    assert!(try_from_str("foo").is_err());
-    assert!(try_from_str("-1.2345").is_err());
-    assert!(try_from_str("-1.23456").is_err());
-
-    assert_eq!(try_from_str("1.2345")?, Some(dec!(1.2345)));
-    assert_eq!(try_from_str("0.0001")?, Some(dec!(0.0001)));
+    assert_eq!(try_from_str_default("")?, None);
+    assert_eq!(try_from_str_default("1.1")?, Some(dec!(1.1)));
+    assert_eq!(try_from_str_default(" 1.1 ")?, Some(dec!(1.1)));
+
+    assert!(try_from_str_default("0.0").is_err());
+    assert!(try_from_str_default("0").is_err());
+    assert!(try_from_str_default("0.23456").is_err());
+    assert!(try_from_str_default("0.234.56").is_err());
+    assert!(try_from_str_default("0.2345.6").is_err());
+    assert!(try_from_str_default(".2345").is_err());
+    assert!(try_from_str_default("10.23456").is_err());
+    assert!(try_from_str_default("foo").is_err());
+    assert!(try_from_str_default("-1.2345").is_err());
+    assert!(try_from_str_default("-1.23456").is_err());
+
+    assert_eq!(try_from_str_default("1.2345")?, Some(dec!(1.2345)));
+    assert_eq!(try_from_str_default("0.0001")?, Some(dec!(0.0001)));
     Ok(())
 }
 
 #[test]
 fn test_deserialize_with_amount() -> Result<(), Error> {
-    use csv::StringRecord;
     use rust_decimal_macros::dec;
 
     let expected = Transaction::new(TranType::Deposit, ClientId(1), TxId(2), Some(dec!(1.1)));
@@ -172,8 +470,6 @@ fn test_deserialize_with_amount() -> Result<(), Error> {
 
 #[test]
 fn test_deserialize_no_amount() -> Result<(), Error> {
-    use csv::StringRecord;
-
     let expected = Transaction::new(TranType::Dispute, ClientId(1), TxId(2), None);
 
     let h = StringRecord::from(vec!["type", "client", "tx", "amount"]);
@@ -187,7 +483,7 @@ fn test_deserialize_no_amount() -> Result<(), Error> {
         t,
         &Transaction {
             tran_type: TranType::Resolve,
-            ..expected
+            ..expected.clone()
         }
     );
 
@@ -206,8 +502,6 @@ fn test_deserialize_no_amount() -> Result<(), Error> {
 
 #[test]
 fn test_deserialize_err() -> Result<(), Error> {
-    use csv::StringRecord;
-
     let h = StringRecord::from(vec!["type", "client", "tx", "amount", "random"]);
 
     assert!(&StringRecord::from_iter("deposit,1,2,1.1,1".split(","))
@@ -246,3 +540,303 @@ fn test_deserialize_err() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_from_str_grouping() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let opts = ParseOptions {
+        allow_grouping: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        try_from_str("1,234.56", &opts, MAX_DP)?,
+        Some(dec!(1234.56))
+    );
+    assert_eq!(
+        try_from_str("1,234,567", &opts, MAX_DP)?,
+        Some(dec!(1234567))
+    );
+    assert_eq!(try_from_str("123.4", &opts, MAX_DP)?, Some(dec!(123.4)));
+
+    assert!(try_from_str("1,23.4", &opts, MAX_DP).is_err());
+    assert!(try_from_str(",234.5", &opts, MAX_DP).is_err());
+    assert!(try_from_str("1234,567", &opts, MAX_DP).is_err());
+    assert!(try_from_str("1.234,5", &opts, MAX_DP).is_err());
+
+    // grouping is rejected unless enabled
+    assert!(try_from_str_default("1,234.56").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_from_str_trim_trailing_zeros() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let opts = ParseOptions {
+        trim_trailing_zeros: true,
+        ..Default::default()
+    };
+    assert_eq!(try_from_str("1.23000", &opts, MAX_DP)?, Some(dec!(1.23)));
+    assert_eq!(try_from_str("1.230000", &opts, MAX_DP)?, Some(dec!(1.23)));
+    assert_eq!(try_from_str("100.00000", &opts, MAX_DP)?, Some(dec!(100)));
+    // genuinely too precise is still rejected, not rounded
+    assert!(try_from_str("1.23456", &opts, MAX_DP).is_err());
+    assert!(try_from_str("0.00000", &opts, MAX_DP).is_err());
+
+    // the zeros count towards the limit unless enabled
+    assert!(try_from_str_default("1.23000").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_deserialize_grouping() -> Result<(), Error> {
+    use csv::ReaderBuilder;
+    use rust_decimal_macros::dec;
+
+    let opts = ParseOptions {
+        allow_grouping: true,
+        ..Default::default()
+    };
+
+    // quoted amount is a single field, commas stripped
+    let data = "type,client,tx,amount\ndeposit,1,2,\"1,234.56\"\n";
+    let mut rdr = ReaderBuilder::new().from_reader(data.as_bytes());
+    let headers = rdr.headers()?.clone();
+    let record = rdr.records().next().unwrap()?;
+    let raw: RawTransaction = record.deserialize(Some(&headers))?;
+    let t = Transaction::from_raw(raw, &opts)?;
+    assert_eq!(
+        t,
+        Transaction::new(TranType::Deposit, ClientId(1), TxId(2), Some(dec!(1234.56)))
+    );
+
+    // unquoted amount is read as an extra field
+    let data = "type,client,tx,amount\ndeposit,1,2,1,234.56\n";
+    let mut rdr = ReaderBuilder::new().from_reader(data.as_bytes());
+    let err = rdr.records().next().unwrap().unwrap_err();
+    assert!(matches!(err.kind(), csv::ErrorKind::UnequalLengths { .. }));
+
+    Ok(())
+}
+
+#[test]
+fn test_type_whitespace() -> Result<(), Error> {
+    use csv::{ReaderBuilder, Trim};
+    use rust_decimal_macros::dec;
+
+    let data = "type,client,tx,amount\n  dep osit ,1,2,1.5\n";
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(data.as_bytes());
+    let headers = rdr.headers()?.clone();
+    let record = rdr.records().next().unwrap()?;
+
+    // trimming only takes the ends, the inner space is an unknown type
+    let err = RawTransaction::from_record(&record, &headers, &ParseOptions::default()).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown transaction type 'dep osit'");
+    assert!(format!("{:#}", err).contains("line: 2"), "{:#}", err);
+
+    let opts = ParseOptions {
+        normalize_type_whitespace: true,
+        ..Default::default()
+    };
+    let raw = RawTransaction::from_record(&record, &headers, &opts)?;
+    assert_eq!(
+        Transaction::from_raw(raw, &opts)?,
+        Transaction::new(TranType::Deposit, ClientId(1), TxId(2), Some(dec!(1.5)))
+    );
+
+    // still unknown once the whitespace is gone
+    let data = "type,client,tx,amount\ndep o sit x,1,2,1.5\n";
+    let mut rdr = ReaderBuilder::new().from_reader(data.as_bytes());
+    let record = rdr.records().next().unwrap()?;
+    let err = RawTransaction::from_record(&record, &headers, &opts).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown transaction type 'depositx'");
+    Ok(())
+}
+
+#[test]
+fn test_deserialize_reason() -> Result<(), Error> {
+    let h = StringRecord::from(vec!["type", "client", "tx", "amount", "reason"]);
+    let t = StringRecord::from_iter("chargeback,1,2,,fraud".split(","))
+        .deserialize::<Transaction>(Some(&h))?;
+    assert_eq!(
+        t,
+        Transaction::new(TranType::Chargeback, ClientId(1), TxId(2), None)
+            .with_reason(Some("fraud".to_string()))
+    );
+
+    // empty reason is the same as absent
+    let t = StringRecord::from_iter("chargeback,1,2,,".split(","))
+        .deserialize::<Transaction>(Some(&h))?;
+    assert_eq!(t.reason, None);
+
+    // column may be omitted entirely
+    let h = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    let t = StringRecord::from_iter("chargeback,1,2,".split(","))
+        .deserialize::<Transaction>(Some(&h))?;
+    assert_eq!(t.reason, None);
+
+    // only chargebacks may carry a reason
+    let h = StringRecord::from(vec!["type", "client", "tx", "amount", "reason"]);
+    assert!(StringRecord::from_iter("deposit,1,2,1.0,fraud".split(","))
+        .deserialize::<Transaction>(Some(&h))
+        .is_err());
+    assert!(StringRecord::from_iter("dispute,1,2,,fraud".split(","))
+        .deserialize::<Transaction>(Some(&h))
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_asset_dp() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let opts = ParseOptions {
+        asset_dp: HashMap::from([
+            (AssetId("JPY".to_string()), 0),
+            (AssetId("BTC".to_string()), 8),
+        ]),
+        ..Default::default()
+    };
+    let h = StringRecord::from(vec!["type", "client", "tx", "asset", "amount"]);
+    let parse = |row: &str| -> Result<Transaction, Error> {
+        let raw: RawTransaction = StringRecord::from_iter(row.split(",")).deserialize(Some(&h))?;
+        Transaction::from_raw(raw, &opts)
+    };
+
+    assert_eq!(opts.finest_dp(), 8);
+
+    // JPY has no minor unit
+    assert!(parse("deposit,1,1,JPY,100.5").is_err());
+    let t = parse("deposit,1,1,JPY,100")?;
+    assert_eq!(t.amount, Some(dec!(100)));
+    assert_eq!(t.asset, Some(AssetId("JPY".to_string())));
+
+    // BTC goes to satoshis
+    let t = parse("deposit,1,2,BTC,0.12345678")?;
+    assert_eq!(t.amount, Some(dec!(0.12345678)));
+    assert!(parse("deposit,1,2,BTC,0.123456789").is_err());
+
+    // anything else gets the default
+    assert!(parse("deposit,1,3,USD,1.2345").is_ok());
+    assert!(parse("deposit,1,3,USD,1.23456").is_err());
+
+    // as does a file with no asset column
+    let h = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    let raw: RawTransaction =
+        StringRecord::from_iter("deposit,1,3,1.23456".split(",")).deserialize(Some(&h))?;
+    assert!(Transaction::from_raw(raw, &opts).is_err());
+    let opts = ParseOptions {
+        max_dp: 5,
+        ..Default::default()
+    };
+    let raw: RawTransaction =
+        StringRecord::from_iter("deposit,1,3,1.23456".split(",")).deserialize(Some(&h))?;
+    assert_eq!(
+        Transaction::from_raw(raw, &opts)?.amount,
+        Some(dec!(1.23456))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_admin_types() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let h = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    let parse = |row: &str, opts: &ParseOptions| -> Result<Transaction, Error> {
+        let raw: RawTransaction = StringRecord::from_iter(row.split(",")).deserialize(Some(&h))?;
+        Transaction::from_raw(raw, opts)
+    };
+
+    // rejected unless asked for
+    assert!(parse("mint,1,1,5.0", &ParseOptions::default()).is_err());
+    assert!(parse("burn,1,1,5.0", &ParseOptions::default()).is_err());
+
+    let opts = ParseOptions {
+        allow_admin: true,
+        ..Default::default()
+    };
+    let t = parse("mint,1,1,5.0", &opts)?;
+    assert_eq!(
+        t,
+        Transaction::new(TranType::Mint, ClientId(1), TxId(1), Some(dec!(5.0)))
+    );
+    assert_eq!(parse("burn,1,2,1.0", &opts)?.tran_type, TranType::Burn);
+    assert!(parse("burn,1,2,", &opts).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_dispute_flow_fields() -> Result<(), Error> {
+    let h = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    for (row, err) in [
+        ("dispute,1,2,1.0", "amount not allowed for dispute"),
+        ("resolve,1,2,1.0", "amount not allowed for resolve"),
+        ("chargeback,1,2,abc", "amount not allowed for chargeback"),
+    ] {
+        let raw: RawTransaction = StringRecord::from_iter(row.split(",")).deserialize(Some(&h))?;
+        let e = Transaction::from_raw(raw, &ParseOptions::default()).unwrap_err();
+        assert_eq!(e.to_string(), err);
+    }
+
+    // an empty amount is the same as absent
+    let raw: RawTransaction =
+        StringRecord::from_iter("dispute,1,2, ".split(",")).deserialize(Some(&h))?;
+    assert!(Transaction::from_raw(raw, &ParseOptions::default()).is_ok());
+    Ok(())
+}
+
+#[test]
+fn test_validate_record() -> Result<(), Error> {
+    use rust_decimal_macros::dec;
+
+    let record = |s: &str| StringRecord::from_iter(s.split(","));
+    let opts = ParseOptions::default();
+    let h = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    assert_eq!(
+        validate_record(&h, &record("deposit,1,2,1.1"), &opts)?,
+        Transaction::new(TranType::Deposit, ClientId(1), TxId(2), Some(dec!(1.1)))
+    );
+    assert_eq!(
+        validate_record(&h, &record("chargeback,1,2,"), &opts)?,
+        Transaction::new(TranType::Chargeback, ClientId(1), TxId(2), None)
+    );
+
+    // header order doesn't matter
+    let reordered = StringRecord::from(vec!["client", "type", "tx", "amount"]);
+    assert_eq!(
+        validate_record(&reordered, &record("1,withdrawal,2,1.1"), &opts)?.tran_type,
+        TranType::Withdrawal
+    );
+
+    for bad in [
+        "depositd,1,2,1.1",
+        "deposit,1,2,",
+        "deposit,1,2,0",
+        "deposit,1,2,1.23456",
+        "deposit,-1,2,1.1",
+        "dispute,1,2,1.1",
+        "mint,1,2,1.1",
+    ] {
+        assert!(validate_record(&h, &record(bad), &opts).is_err(), "{}", bad);
+    }
+    let extra = StringRecord::from(vec!["type", "client", "tx", "amount", "random"]);
+    assert!(validate_record(&extra, &record("deposit,1,2,1.1,1"), &opts).is_err());
+
+    // the run's options apply, not the defaults
+    let opts = ParseOptions {
+        max_dp: 5,
+        asset_dp: HashMap::from([(AssetId("JPY".to_string()), 0)]),
+        ..Default::default()
+    };
+    assert!(validate_record(&h, &record("deposit,1,2,1.23456"), &opts).is_ok());
+    let assets = StringRecord::from(vec!["type", "client", "tx", "asset", "amount"]);
+    assert!(validate_record(&assets, &record("deposit,1,2,JPY,1.5"), &opts).is_err());
+    assert!(validate_record(&assets, &record("deposit,1,2,JPY,15"), &opts).is_ok());
+    Ok(())
+}
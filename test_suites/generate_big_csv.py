@@ -3,10 +3,11 @@ import argparse
 
 parser = argparse.ArgumentParser(description='Generate a very big CSV file.')
 parser.add_argument('--rows', type=int, help='how many rows to generate')
+parser.add_argument('--clients', type=int, default=128, help='how many distinct clients to spread rows over')
 args = parser.parse_args()
 
 print("type,client,tx,amount")
 
 for i in range(0, args.rows):
-    client = i % 128
+    client = i % args.clients
     print(f"deposit,{client},{i},1")